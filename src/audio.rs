@@ -5,9 +5,9 @@ use std::sync::mpsc;
 use sample::{Sample, FromSample, ToFrameSliceMut};
 
 const CHANNELS: usize = 2;
-const FRAMES: u32 = 64;
+pub const FRAMES: u32 = 64;
 pub const SAMPLE_HZ: u64 = 44_100;
-const DT: f64 = FRAMES as f64 / SAMPLE_HZ as f64;
+pub const DT: f64 = FRAMES as f64 / SAMPLE_HZ as f64;
 
 pub fn run_with<F,G>(f: F, mut audio_requested: G) -> Result<(), pa::Error>
    where F: FnOnce(), G: FnMut(&mut [[f32; CHANNELS]], f64) + 'static
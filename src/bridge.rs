@@ -0,0 +1,144 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// A single-producer single-consumer ring of frames between the rendering thread and the audio
+// callback. Neither side ever waits on the other: the producer pushes as many frames as there
+// is room for, and the consumer plays silence for the frames that are missing, counting every
+// callback that came up short as an underrun.
+pub fn new<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+   assert!(capacity > 0);
+   let ring = Arc::new(Ring{
+      frames: (0..capacity).map(|_| UnsafeCell::new(T::default())).collect(),
+      written: AtomicUsize::new(0),
+      read: AtomicUsize::new(0),
+      underruns: AtomicU64::new(0),
+   });
+   (Producer{ring: ring.clone()}, Consumer{ring})
+}
+
+// `written` and `read` count frames since the start. Frames between the two belong to the
+// consumer, the others to the producer, and each side only advances its own counter.
+struct Ring<T> {
+   frames: Box<[UnsafeCell<T>]>,
+   written: AtomicUsize,
+   read: AtomicUsize,
+   underruns: AtomicU64,
+}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+   fn slot(&self, n: usize) -> *mut T { self.frames[n % self.frames.len()].get() }
+}
+
+pub struct Producer<T> {
+   ring: Arc<Ring<T>>,
+}
+impl<T: Copy> Producer<T> {
+   pub fn free(&self) -> usize {
+      self.ring.frames.len() - self.ring.written.load(Ordering::Relaxed).wrapping_sub(self.ring.read.load(Ordering::Acquire))
+   }
+
+   // Appends as many of `frames` as fit, returning their number.
+   pub fn push(&mut self, frames: &[T]) -> usize {
+      let written = self.ring.written.load(Ordering::Relaxed);
+      let n = frames.len().min(self.free());
+      frames[..n].iter().enumerate().for_each(|(i, frame)| unsafe { *self.ring.slot(written.wrapping_add(i)) = *frame });
+      self.ring.written.store(written.wrapping_add(n), Ordering::Release);
+      n
+   }
+
+   pub fn underruns(&self) -> u64 { self.ring.underruns.load(Ordering::Relaxed) }
+
+   // The consumer is gone, as when the audio stream has been closed.
+   pub fn is_closed(&self) -> bool { Arc::strong_count(&self.ring) == 1 }
+}
+
+pub struct Consumer<T> {
+   ring: Arc<Ring<T>>,
+}
+impl<T: Copy + Default> Consumer<T> {
+   // Fills `out` with the oldest frames, and with silence past the last one.
+   pub fn pop(&mut self, out: &mut [T]) {
+      let read = self.ring.read.load(Ordering::Relaxed);
+      let n = out.len().min(self.ring.written.load(Ordering::Acquire).wrapping_sub(read));
+      out.iter_mut().enumerate().for_each(|(i, frame)|
+         *frame = if i < n { unsafe { *self.ring.slot(read.wrapping_add(i)) } } else { T::default() }
+      );
+      self.ring.read.store(read.wrapping_add(n), Ordering::Release);
+      if n < out.len() {
+         self.ring.underruns.fetch_add(1, Ordering::Relaxed);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn frames_wrap_around_the_ring() {
+      let (mut producer, mut consumer) = new::<u32>(4);
+      let mut out = [0; 4];
+      assert_eq!(producer.push(&[1, 2, 3]), 3);
+      consumer.pop(&mut out[..2]);
+      assert_eq!(out[..2], [1, 2]);
+      assert_eq!(producer.push(&[4, 5, 6, 7]), 3);
+      consumer.pop(&mut out);
+      assert_eq!(out, [3, 4, 5, 6]);
+      assert_eq!(producer.free(), 4);
+      assert_eq!(producer.underruns(), 0);
+   }
+
+   #[test]
+   fn full_rings_refuse_frames_and_empty_rings_play_silence() {
+      let (mut producer, mut consumer) = new::<u32>(3);
+      assert_eq!(producer.push(&[1, 2, 3, 4, 5]), 3);
+      assert_eq!(producer.free(), 0);
+      assert_eq!(producer.push(&[6]), 0);
+
+      let mut out = [9; 5];
+      consumer.pop(&mut out);
+      assert_eq!(out, [1, 2, 3, 0, 0]);
+      assert_eq!(producer.underruns(), 1);
+      consumer.pop(&mut out[..2]);
+      assert_eq!(out[..2], [0, 0]);
+      assert_eq!(producer.underruns(), 2);
+      consumer.pop(&mut []);
+      assert_eq!(producer.underruns(), 2);
+      assert_eq!(producer.free(), 3);
+
+      assert!(!producer.is_closed());
+      drop(consumer);
+      assert!(producer.is_closed());
+   }
+
+   #[test]
+   fn frames_arrive_in_order_across_threads() {
+      const FRAMES: u32 = 100_000;
+      let (mut producer, mut consumer) = new::<u32>(61);
+      let rendering = std::thread::spawn(move || {
+         let frames: Vec<u32> = (1..=FRAMES).collect();
+         let (mut pushed, mut chunk) = (0, 1);
+         while pushed < frames.len() {
+            let end = (pushed + chunk).min(frames.len());
+            pushed += producer.push(&frames[pushed..end]);
+            chunk = chunk % 97 + 1;
+            std::thread::yield_now();
+         }
+      });
+
+      // Frames past the ones that arrived are silence, zero here.
+      let (mut next, mut out) = (1, [0; 13]);
+      while next <= FRAMES {
+         consumer.pop(&mut out);
+         for &frame in out.iter().take_while(|&&frame| frame != 0) {
+            assert_eq!(frame, next);
+            next += 1;
+         }
+         assert!(out.iter().skip_while(|&&frame| frame != 0).all(|&frame| frame == 0));
+         std::thread::yield_now();
+      }
+      rendering.join().unwrap();
+   }
+}
@@ -4,74 +4,105 @@
 mod parser_org;
 mod ui;
 mod audio;
+mod bridge;
 mod processing;
 
 use crate::ui::SynthToUiMessage;
-use processing::processor::{Buffer, GenericSampledBuffer};
-use processing::element::Element;
-use processing::prim_element::PrimElement;
-use processing::Value;
+use processing::{
+   FlowId, InputNo, OutputNo, Type, PrimType, Value, Store,
+   flow::Node, element::Element, prim_element::PrimElement, engine::{Engine, Renderer}, processor::{Buffer, GenericSampledBuffer},
+};
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::mpsc;
+use std::time::Duration;
 
 pub enum UiToSynthMessage {
    ChangeFreq(u32, f64),
 }
 
 const F_NYQ: u64 = audio::SAMPLE_HZ / 2;
+const LOOKAHEAD_BLOCKS: usize = 4;
 
 fn main() {
    let (tx, rx) = mpsc::channel();
 
-   let mut store = processing::Store::new();
-   let global_flow = store.add_flow();
-
-   let output_type = processing::Type::Sampled{ty: processing::PrimType::F32, f_nyq: F_NYQ};
-
-   let (out_l, out_l_ix) = store.add_output(global_flow, output_type);
-   let (out_r, out_r_ix) = store.add_output(global_flow, output_type);
-
-
-   let freq = store.add_element(global_flow, Element::Prim(PrimElement::Constant{value: Value::F32(440.0.into()), f_nyq: F_NYQ}));
-   let a_m = store.add_element(global_flow, Element::Prim(PrimElement::Constant{value: Value::F32(10.0.into()), f_nyq: F_NYQ}));
-   let osc = store.add_element(global_flow, Element::Prim(PrimElement::SineOscF32{f_nyq: F_NYQ}));
-   let osc2 = store.add_element(global_flow, Element::Prim(PrimElement::SineOscF32{f_nyq: F_NYQ}));
-   let amp = store.add_element(global_flow, Element::Prim(PrimElement::Constant{value: Value::F32(440.0.into()), f_nyq: F_NYQ}));
-
-   store.add_edge(global_flow, freq, 0.into(), osc, 0.into()).unwrap();
-   store.add_edge(global_flow, osc, 0.into(), out_l_ix, 0.into()).unwrap();
-   store.add_edge(global_flow, osc, 0.into(), out_r_ix, 0.into()).unwrap();
-
-
-   let out_buffer = Arc::new(Mutex::new( (Buffer::new(output_type), Buffer::new(output_type)) ));
-
-   let (request_tx, request_rx) = mpsc::channel();
-   let (response_tx, response_rx) = mpsc::channel();
+   let mut args = std::env::args().skip(1);
+   let patch_path = args.next().expect("usage: orgument <patch> [lookahead blocks]");
+   let lookahead = args.next().map_or(LOOKAHEAD_BLOCKS, |arg| arg.parse().expect("lookahead must be a number of blocks"));
+   let store = processing::patch::load(&patch_path).unwrap();
+   let global_flow = store.flow_store().ids().next().expect("patch contains no flow");
+   let (engine, mut renderer) = processing::engine::new(&store, audio::FRAMES as usize);
+   renderer.set_threads(std::thread::available_parallelism().map_or(1, usize::from));
+
+   // Output 0 is played, in stereo or in mono on both channels.
+   let output_type = match store.flow_store()[global_flow].output_types().find(|(no, _)| *no == OutputNo::from(0)) {
+      Some((_, ty @ Type::Bus{ty: PrimType::F32, f_nyq: F_NYQ, channels: 2})) | Some((_, ty @ Type::Sampled{ty: PrimType::F32, f_nyq: F_NYQ})) => ty,
+      other => {
+         eprintln!("{}: output 0 must be an f32 stream or stereo bus at {} Hz, not {:?}", patch_path, audio::SAMPLE_HZ, other.map(|(_, ty)| ty));
+         std::process::exit(1)
+      }
+   };
+   let mut out_buffer = Buffer::new(output_type);
+   let (mut producer, mut consumer) = bridge::new(lookahead.max(1) * audio::FRAMES as usize);
+   render_ahead(&mut renderer, global_flow, &mut out_buffer, &mut producer);
 
-   let out_buffer_for_processing_thread = out_buffer.clone();
    let _processing_thread = std::thread::spawn(move || {
-      while let Ok(buffer_sz) = request_rx.recv() {
-         let mut out_buffer = out_buffer_for_processing_thread.lock().unwrap();
-         let (ref mut out_buffer_l, ref mut out_buffer_r) = *out_buffer;
-         let mut output = std::iter::once((out_l, out_buffer_l)).chain(std::iter::once((out_r, out_buffer_r))).collect();
-         let input = std::iter::empty().collect();
+      let mut reported = 0;
+      while !producer.is_closed() {
+         render_ahead(&mut renderer, global_flow, &mut out_buffer, &mut producer);
+         let underruns = producer.underruns();
+         if underruns != reported {
+            eprintln!("audio underrun: {} so far", underruns);
+            reported = underruns;
+         }
+         std::thread::sleep(Duration::from_secs_f64(audio::DT / 2.0));
+      }
+   });
+
+   let _control_thread = std::thread::spawn(move || control(store, global_flow, engine, rx));
 
-         store.compute_outplace(global_flow, &mut output, &input, buffer_sz);
+   audio::run_with(|| ui::run(tx), move |buffer, _| consumer.pop(buffer)).unwrap();
+}
 
-         response_tx.send(()).unwrap();
+// Edits the store as the UI asks and hands each edited store to the renderer. A track is a sine
+// oscillator of the played flow, counted in node order, whose frequency is the default of its
+// frequency input, so it is only heard while that input is unconnected. Frequencies are played
+// rather than edited, and are left out of the undo history.
+fn control(mut store: Store, flow_id: FlowId, mut engine: Engine, rx: mpsc::Receiver<UiToSynthMessage>) {
+   while let Ok(message) = rx.recv() {
+      match message {
+         UiToSynthMessage::ChangeFreq(track, hz) => {
+            let osc = store.flow_store()[flow_id].nodes()
+               .filter(|(_, node)| matches!(node, Node::Element(Element::Prim(PrimElement::SineOscF32{..}))))
+               .nth(track as usize).map(|(node_ix, _)| node_ix);
+            if let Some(osc) = osc {
+               match store.set_live_default(flow_id, osc, InputNo::from(0), Some(Value::F32((hz as f32).into()))) {
+                  Ok(_) => { engine.commit(&store); }
+                  Err(error) => eprintln!("track {}: cannot set frequency to {} Hz: {:?}", track, hz, error),
+               }
+            }
+         }
       }
-   });
+   }
+}
 
-   audio::run_with(|| ui::run(tx), move |buffer, _| {
-      request_tx.send(buffer.len()).unwrap();
-      response_rx.recv().unwrap();
-      
-      let out_buffer = out_buffer.lock().unwrap();
-      let (ref out_buffer_l, ref out_buffer_r) = *out_buffer;
-      buffer.iter_mut().zip(
-         unwrap_match!((out_buffer_l, out_buffer_r),
-            (Buffer::Sampled(GenericSampledBuffer::F32(l)), Buffer::Sampled(GenericSampledBuffer::F32(r)))
-               => l.samples.iter().zip(&r.samples))
-      ).for_each(|(dst, (src_l, src_r))| *dst = [*src_l, *src_r]);
-   }).unwrap();
-}
\ No newline at end of file
+// Renders blocks until the bridge has no room for another one.
+fn render_ahead(renderer: &mut Renderer, flow_id: FlowId, out_buffer: &mut Buffer, producer: &mut bridge::Producer<[f32; 2]>) {
+   let buffer_sz = audio::FRAMES as usize;
+   while producer.free() >= buffer_sz {
+      let mut output = std::iter::once((OutputNo::from(0), &mut *out_buffer)).collect();
+      let input = std::iter::empty().collect();
+      renderer.compute_outplace(flow_id, &mut output, &input, buffer_sz);
+
+      let frames: Vec<_> = match &*out_buffer {
+         Buffer::Bus(channels) => match &channels[..] {
+            [Buffer::Sampled(GenericSampledBuffer::F32(l)), Buffer::Sampled(GenericSampledBuffer::F32(r))] =>
+               l.samples.iter().zip(&r.samples).map(|(l, r)| [*l, *r]).collect(),
+            _ => unreachable!(),
+         }
+         Buffer::Sampled(GenericSampledBuffer::F32(x)) => x.samples.iter().map(|x| [*x, *x]).collect(),
+         _ => unreachable!(),
+      };
+      producer.push(&frames);
+   }
+}
@@ -1,30 +1,56 @@
 use super::{
-   flow::Flow, flow_store::{FlowStore, FlowId},
-   Type, PrimType, InputNo, OutputNo, processor::{ProcessorStore, Buffer},
+   flow_store::{FlowStore, FlowId},
+   Type, InputNo, OutputNo, Port,
    prim_element::PrimElement,
+   template::{Arg, Substitution},
 };
 
 use linear_map::{LinearMap, set::LinearSet};
-use std::cell::{Ref, RefMut};
-use std::ops::{Deref, DerefMut};
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Element {
    Flow(FlowId),
+   Template(FlowId, Vec<Arg>),
    Prim(PrimElement),
+   // One instance of the element per channel, with buses on its sampled ports.
+   PerChannel(PrimElement, u32),
 }
 impl Element {
    pub fn input_types(&self, store: &FlowStore) -> LinearMap<InputNo, Type> {
       match self {
          Element::Flow(flow) => store.get(*flow).unwrap().input_types().collect(),
+         Element::Template(flow, args) => {
+            let flow = store.get(*flow).unwrap();
+            Substitution::new(flow, args).input_types(flow)
+         }
          Element::Prim(prim) => prim.input_types(),
+         Element::PerChannel(prim, channels) => prim.input_types().into_iter().map(|(no, ty)| (no, ty.bus(*channels))).collect(),
       }
    }
 
    pub fn output_types(&self, store: &FlowStore) -> LinearMap<OutputNo, Type> {
       match self {
          Element::Flow(flow) => store.get(*flow).unwrap().output_types().collect(),
+         Element::Template(flow, args) => {
+            let flow = store.get(*flow).unwrap();
+            Substitution::new(flow, args).output_types(flow)
+         }
          Element::Prim(prim) => prim.output_types(),
+         Element::PerChannel(prim, channels) => prim.output_types().into_iter().map(|(no, ty)| (no, ty.bus(*channels))).collect(),
+      }
+   }
+
+   pub fn input_ports(&self, store: &FlowStore) -> LinearMap<InputNo, Port> {
+      match self {
+         Element::Flow(flow) | Element::Template(flow, _) => store.get(*flow).unwrap().input_ports().collect(),
+         Element::Prim(prim) | Element::PerChannel(prim, _) => prim.input_ports(),
+      }
+   }
+
+   pub fn output_ports(&self, store: &FlowStore) -> LinearMap<OutputNo, Port> {
+      match self {
+         Element::Flow(flow) | Element::Template(flow, _) => store.get(*flow).unwrap().output_ports().collect(),
+         Element::Prim(prim) | Element::PerChannel(prim, _) => prim.output_ports(),
       }
    }
 
@@ -36,14 +62,42 @@ impl Element {
       self.output_types(store).keys().copied().collect()
    }
 
-   pub fn compute_outplace(
-      &self, output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>, buffer_sz: usize,
-      flow_store: &FlowStore, processor_store: &ProcessorStore
-   )
-   {
+   // The flow run by this element, if it is not primitive.
+   pub fn flow_id(&self) -> Option<FlowId> {
       match self {
-         Element::Flow(flow_id) => processor_store.compute_outplace(*flow_id, output, input, buffer_sz, flow_store),
-         Element::Prim(prim_id) => processor_store.compute_outplace_prim(*prim_id, output, input, buffer_sz, flow_store),
+         Element::Flow(flow) | Element::Template(flow, _) => Some(*flow),
+         Element::Prim(_) | Element::PerChannel(..) => None,
       }
    }
-}
\ No newline at end of file
+
+   // This element retyped to accept a stream of type `ty` on `input_no`, if it is primitive
+   // and generic over that input. Any primitive element fed a bus of its input type runs per
+   // channel.
+   pub fn infer(&self, input_no: InputNo, ty: Type) -> Option<Element> {
+      let prim = option_match!(self, Element::Prim(prim) | Element::PerChannel(prim, _) => *prim)?;
+      match ty {
+         Type::Bus{channels, ..} => prim.infer(input_no, ty.channel())
+            .or_else(|| Some(prim).filter(|prim| prim.input_types().get(&input_no) == Some(&ty.channel())))
+            .map(|prim| Element::PerChannel(prim, channels)),
+         ty => prim.infer(input_no, ty).map(Element::Prim),
+      }
+   }
+
+   // The chain of converters that turns a stream of type `from` into one of type `to`. Buses
+   // are downmixed to mono first and upmixed last, unless they keep their channels, which are
+   // then converted one by one.
+   pub fn converters(from: Type, to: Type) -> Option<Vec<Element>> {
+      let converters = PrimElement::converters(from.channel(), to.channel())?;
+      let from_bus = option_match!(from, Type::Bus{ty, f_nyq, channels} => (ty, f_nyq, channels));
+      let to_bus = option_match!(to, Type::Bus{ty, f_nyq, channels} => (ty, f_nyq, channels));
+      if let (Some((.., from_channels)), Some((.., to_channels))) = (from_bus, to_bus) {
+         if from_channels == to_channels {
+            return Some(converters.into_iter().map(|converter| Element::PerChannel(converter, from_channels)).collect())
+         }
+      }
+
+      let downmix = from_bus.map(|(ty, f_nyq, channels)| Element::Prim(PrimElement::Downmix{ty, f_nyq, channels}));
+      let upmix = to_bus.map(|(ty, f_nyq, channels)| Element::Prim(PrimElement::Upmix{ty, f_nyq, channels}));
+      Some(downmix.into_iter().chain(converters.into_iter().map(Element::Prim)).chain(upmix).collect())
+   }
+}
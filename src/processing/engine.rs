@@ -0,0 +1,313 @@
+use super::{Store, FlowId, Buffer, InputNo, OutputNo, Type, processor::{rate, samples_at}};
+
+use linear_map::LinearMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc;
+
+// Splits processing into a control side that edits and compiles `Store`s, and a `Renderer`
+// that owns the running snapshot on the audio thread. Snapshots are swapped in at block
+// boundaries, and replaced snapshots are sent back so they are dropped off the audio thread.
+// Independent branches are run on the renderer's workers, if it has any.
+//
+// The renderer runs flows in blocks of `quantum` samples whatever the sizes the host asks for,
+// so that the output is the same however a render is chunked. Flows with inputs are run once
+// a whole quantum of input has arrived, which delays their outputs by one quantum, and the
+// others are rendered ahead.
+pub fn new(store: &Store, quantum: usize) -> (Engine, Renderer) {
+   assert!(quantum > 0);
+   let (program_tx, program_rx) = mpsc::channel();
+   let (garbage_tx, garbage_rx) = mpsc::channel();
+   (
+      Engine{program_tx, garbage_rx},
+      Renderer{store: store.snapshot(), program_rx, garbage_tx, workers: None, quantum, queues: None},
+   )
+}
+
+pub struct Engine {
+   program_tx: mpsc::Sender<Store>,
+   garbage_rx: mpsc::Receiver<Store>,
+}
+impl Engine {
+   pub fn commit(&mut self, store: &Store) -> bool {
+      self.collect_garbage();
+      self.program_tx.send(store.snapshot()).is_ok()
+   }
+
+   pub fn collect_garbage(&mut self) {
+      while self.garbage_rx.try_recv().is_ok() {}
+   }
+}
+
+pub struct Renderer {
+   store: Store,
+   program_rx: mpsc::Receiver<Store>,
+   garbage_tx: mpsc::Sender<Store>,
+   workers: Option<rayon::ThreadPool>,
+   quantum: usize,
+   queues: Option<Queues>,
+}
+impl Renderer {
+   pub fn set_threads(&mut self, threads: usize) {
+      self.workers = Some(threads).filter(|&threads| threads > 1)
+         .map(|threads| rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap());
+   }
+
+   pub fn compute_outplace<BufferRefMut>(
+      &mut self, flow_id: FlowId, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRefMut>, buffer_sz: usize,
+   ) where BufferRefMut: DerefMut<Target=Buffer>
+   {
+      self.swap_program();
+      if let Some(flow) = self.store.flow_store().get(flow_id) {
+         let current = matches!(&self.queues, Some(queues) if queues.flow_id == flow_id
+            && queues.input_types.iter().copied().eq(flow.input_types()) && queues.output_types.iter().copied().eq(flow.output_types()));
+         if !current {
+            let processor = self.store.processor_store().processor(flow_id);
+            let (inputs, outputs) = (flow.input_types().collect(), flow.output_types().collect());
+            self.queues = Some(Queues::new(flow_id, inputs, outputs, processor.f_nyq(), processor.clock(), self.quantum));
+         }
+         let queues = self.queues.as_mut().unwrap();
+         queues.push_input(input, buffer_sz);
+         while queues.needs_quantum(buffer_sz) {
+            queues.render_quantum(&self.store, self.workers.as_ref());
+         }
+         queues.pop_output(output, buffer_sz);
+      }
+      else {
+         output.iter_mut().for_each(|(_, buffer)| {
+            buffer.update_size(buffer_sz);
+            buffer.clear();
+         });
+      }
+   }
+
+   fn swap_program(&mut self) {
+      while let Ok(mut store) = self.program_rx.try_recv() {
+         store.adopt_state(&mut self.store);
+         let old = std::mem::replace(&mut self.store, store);
+         let _ = self.garbage_tx.send(old);
+      }
+   }
+}
+
+// The samples and events between the host's blocks and the quanta. Times are counted at the
+// reference rate from when the queues were made: the host has asked for everything before
+// `time` and the flow has been run up to `rendered`. Input queues start at `rendered` and
+// output queues at `time`, less the latency. The buffers a quantum is run on are kept from one
+// quantum to the next, boxed to be passed as buffer references.
+struct Queues {
+   flow_id: FlowId,
+   input_types: Vec<(InputNo, Type)>,
+   output_types: Vec<(OutputNo, Type)>,
+   f_nyq: Option<u64>,
+   quantum: usize,
+   latency: u64,
+   time: u64,
+   rendered: u64,
+   inputs: LinearMap<InputNo, Buffer>,
+   outputs: LinearMap<OutputNo, Buffer>,
+   fronts: LinearMap<InputNo, Box<Buffer>>,
+   blocks: LinearMap<OutputNo, Box<Buffer>>,
+}
+impl Queues {
+   fn new(flow_id: FlowId, input_types: Vec<(InputNo, Type)>, output_types: Vec<(OutputNo, Type)>, f_nyq: Option<u64>, clock: u64, quantum: usize) -> Self {
+      let latency = if input_types.is_empty() { 0 } else { quantum as u64 };
+      // Queues hold up to a quantum and a host block, which is rarely longer than a quantum.
+      let room = |ty| samples_at(f_nyq, rate(ty), 2 * quantum as u64 + latency);
+      let inputs = input_types.iter().map(|&(no, ty)| {
+         let mut queue = Buffer::new(ty);
+         queue.reserve(room(ty));
+         (no, queue)
+      }).collect();
+      let fronts = input_types.iter().map(|&(no, ty)| (no, Box::new(Buffer::new(ty)))).collect();
+      let blocks = output_types.iter().map(|&(no, ty)| (no, Box::new(Buffer::new(ty)))).collect();
+      // Output queues start with the latency as silence, rounded up to whole samples of the port.
+      let outputs = output_types.iter().map(|&(no, ty)| {
+         let mut queue = Buffer::new(ty);
+         queue.reserve(room(ty));
+         queue.update_size(match (f_nyq, rate(ty)) {
+            (Some(reference), Some(f_nyq)) => (latency as u128 * f_nyq as u128).div_ceil(reference as u128) as usize,
+            _ => latency as usize,
+         });
+         queue.clear();
+         (no, queue)
+      }).collect();
+      Self{flow_id, input_types, output_types, f_nyq, quantum, latency, time: clock, rendered: clock, inputs, outputs, fronts, blocks}
+   }
+
+   fn size(&self, ty: Type, from: u64, len: usize) -> usize {
+      samples_at(self.f_nyq, rate(ty), from + len as u64) - samples_at(self.f_nyq, rate(ty), from)
+   }
+
+   fn push_input<BufferRef: Deref<Target=Buffer>>(&mut self, input: &LinearMap<InputNo, BufferRef>, buffer_sz: usize) {
+      for &(no, ty) in &self.input_types {
+         // Flows with inputs are never rendered past what the host has asked for.
+         let offset = self.time - self.rendered;
+         let size = self.size(ty, self.time, buffer_sz);
+         let (queue, silence) = (self.inputs.get_mut(&no).unwrap(), self.fronts.get_mut(&no).unwrap());
+         match input.get(&no) {
+            Some(buffer) => queue.append(buffer, offset),
+            None => {
+               silence.update_size(size);
+               silence.clear();
+               queue.append(silence, offset);
+            }
+         }
+      }
+   }
+
+   fn needs_quantum(&self, buffer_sz: usize) -> bool {
+      let end = self.time + buffer_sz as u64;
+      match self.latency {
+         0 => self.rendered < end,
+         _ => self.rendered + self.quantum as u64 <= end,
+      }
+   }
+
+   fn render_quantum(&mut self, store: &Store, workers: Option<&rayon::ThreadPool>) {
+      let (rendered, quantum) = (self.rendered, self.quantum);
+      for &(no, ty) in &self.input_types {
+         let size = self.size(ty, rendered, quantum);
+         self.inputs.get_mut(&no).unwrap().take_front(self.fronts.get_mut(&no).unwrap(), size, quantum as u64);
+      }
+      match workers {
+         Some(workers) => store.compute_outplace_parallel(workers, self.flow_id, &mut self.blocks, &self.fronts, quantum),
+         None => store.compute_outplace(self.flow_id, &mut self.blocks, &self.fronts, quantum),
+      }
+      let offset = rendered + self.latency - self.time;
+      let blocks = &self.blocks;
+      self.outputs.iter_mut().for_each(|(no, queue)| queue.append(&blocks[no], offset));
+      self.rendered += quantum as u64;
+   }
+
+   fn pop_output<BufferRefMut: DerefMut<Target=Buffer>>(&mut self, output: &mut LinearMap<OutputNo, BufferRefMut>, buffer_sz: usize) {
+      for &(no, ty) in &self.output_types {
+         let size = self.size(ty, self.time, buffer_sz);
+         let queue = self.outputs.get_mut(&no).unwrap();
+         match output.get_mut(&no) {
+            Some(buffer) => queue.take_front(buffer, size, buffer_sz as u64),
+            None => queue.take_front(self.blocks.get_mut(&no).unwrap(), size, buffer_sz as u64),
+         }
+      }
+      self.time += buffer_sz as u64;
+   }
+}
+
+// Renders `len` samples of a flow of `store` with silent inputs, once in blocks of each of
+// `block_sizes`, and checks that all renders are bit for bit the same. Returns the output, or
+// the first block size whose render differs from the first one's.
+pub fn render_block_sizes(store: &Store, flow_id: FlowId, quantum: usize, len: usize, block_sizes: &[usize]) -> Result<LinearMap<OutputNo, Buffer>, usize> {
+   let output_types: Vec<_> = store.flow_store()[flow_id].output_types().collect();
+   let mut first: Option<LinearMap<OutputNo, Buffer>> = None;
+   for &block_sz in block_sizes {
+      assert!(block_sz > 0);
+      let (_, mut renderer) = new(store, quantum);
+      let mut rendered: LinearMap<OutputNo, Buffer> = output_types.iter().map(|&(no, ty)| (no, Buffer::new(ty))).collect();
+      let mut blocks: LinearMap<OutputNo, Buffer> = output_types.iter().map(|&(no, ty)| (no, Buffer::new(ty))).collect();
+      let mut time = 0;
+      while time < len {
+         let buffer_sz = block_sz.min(len - time);
+         let mut output: LinearMap<OutputNo, &mut Buffer> = blocks.iter_mut().map(|(&no, block)| (no, block)).collect();
+         renderer.compute_outplace(flow_id, &mut output, &LinearMap::new(), buffer_sz);
+         rendered.iter_mut().for_each(|(no, buffer)| buffer.append(&blocks[no], time as u64));
+         time += buffer_sz;
+      }
+      match &first {
+         None => first = Some(rendered),
+         Some(first) => if first.iter().any(|(no, buffer)| !buffer.identical(&rendered[no])) { return Err(block_sz) },
+      }
+   }
+   Ok(first.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use super::super::{EdgeMode, PrimType, Value, element::Element, prim_element::{PrimElement, BinaryOp}, processor::{GenericSampledBuffer, GenericEventBuffer}};
+   use std::alloc::{GlobalAlloc, Layout, System};
+   use std::cell::Cell;
+
+   // Counts the allocations of each thread, so that tests running alongside are not counted.
+   struct Counting;
+   thread_local!(static ALLOCATIONS: Cell<usize> = const { Cell::new(0) });
+   unsafe impl GlobalAlloc for Counting {
+      unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+         let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+         System.alloc(layout)
+      }
+      unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+      unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+         let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+         System.realloc(ptr, layout, size)
+      }
+   }
+   #[global_allocator]
+   static COUNTING: Counting = Counting;
+
+   // Events of both inputs are merged into one output and held in another, next to streams
+   // at two rates.
+   #[test]
+   fn rendering_does_not_allocate_once_warmed_up() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let (events, sampled) = (Type::Event(PrimType::F32), Type::Sampled{ty: PrimType::F32, f_nyq: 16000});
+      let (_, a) = store.add_input(flow, "a", events).unwrap();
+      let (_, b) = store.add_input(flow, "b", events).unwrap();
+      let sine = store.add_element(flow, Element::Prim(PrimElement::SineOscF32{f_nyq: 22050})).unwrap();
+      let multiply = store.add_element(flow, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 16000})).unwrap();
+      let (_, merged) = store.add_output(flow, "merged", events).unwrap();
+      let (_, product) = store.add_output(flow, "product", sampled).unwrap();
+      store.add_edge(flow, a, OutputNo(0), merged, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, b, OutputNo(0), merged, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, multiply, OutputNo(0), product, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, sine, OutputNo(0), multiply, InputNo(0), EdgeMode::Convert).unwrap();
+      store.add_edge(flow, a, OutputNo(0), multiply, InputNo(1), EdgeMode::Convert).unwrap();
+
+      let block_sz = 100;
+      let at = |times: &[u64]| {
+         let mut buffer = Buffer::new(events);
+         let events = unwrap_match!(&mut buffer, Buffer::Event(GenericEventBuffer::F32(events)) => events);
+         times.iter().for_each(|&time| events.push(time, time as f32));
+         buffer
+      };
+      let (mut a, mut b) = (at(&[0, 3, 40, 99]), at(&[3, 20, 40]));
+      let input: LinearMap<InputNo, &mut Buffer> = vec![(InputNo(0), &mut a), (InputNo(1), &mut b)].into_iter().collect();
+      let (mut merged, mut product) = (Buffer::new(events), Buffer::new(sampled));
+      let mut output: LinearMap<OutputNo, &mut Buffer> = vec![(OutputNo(0), &mut merged), (OutputNo(1), &mut product)].into_iter().collect();
+      let (_, mut renderer) = new(&store, 64);
+      renderer.compute_outplace(flow, &mut output, &input, block_sz);
+
+      let before = ALLOCATIONS.with(Cell::get);
+      (0..100).for_each(|_| renderer.compute_outplace(flow, &mut output, &input, block_sz));
+      assert_eq!(ALLOCATIONS.with(Cell::get) - before, 0);
+      drop(output);
+      assert_eq!(unwrap_match!(&merged, Buffer::Event(GenericEventBuffer::F32(merged)) => merged.events.len()), 7);
+   }
+
+   // Streams at rates below the reference rate get blocks of varying sizes, and the feedback
+   // edge carries state from one quantum to the next.
+   #[test]
+   fn renders_do_not_depend_on_block_sizes() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let sampled = |f_nyq| Type::Sampled{ty: PrimType::F32, f_nyq};
+      let (_, input) = store.add_input(flow, "in", sampled(22050)).unwrap();
+      let sine = store.add_element(flow, Element::Prim(PrimElement::SineOscF32{f_nyq: 16000})).unwrap();
+      let constant = store.add_element(flow, Element::Prim(PrimElement::Constant{value: Value::F32(0.5.into()), f_nyq: 1000})).unwrap();
+      let add = store.add_element(flow, Element::Prim(PrimElement::Binary{op: BinaryOp::Add, ty: PrimType::F32, f_nyq: 16000})).unwrap();
+      let multiply = store.add_element(flow, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 16000})).unwrap();
+      let (_, sum) = store.add_output(flow, "sum", sampled(16000)).unwrap();
+      let (_, product) = store.add_output(flow, "product", sampled(16000)).unwrap();
+      store.add_edge(flow, sine, OutputNo(0), multiply, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, constant, OutputNo(0), multiply, InputNo(1), EdgeMode::Convert).unwrap();
+      store.add_edge(flow, multiply, OutputNo(0), add, InputNo(1), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, input, OutputNo(0), add, InputNo(0), EdgeMode::Convert).unwrap();
+      store.add_feedback_edge(flow, add, OutputNo(0), add, InputNo(0)).unwrap();
+      store.add_edge(flow, add, OutputNo(0), sum, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, multiply, OutputNo(0), product, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let rendered = render_block_sizes(&store, flow, 64, 3000, &[1, 7, 64, 1000]).unwrap();
+      let product = unwrap_match!(&rendered[&OutputNo(1)], Buffer::Sampled(GenericSampledBuffer::F32(product)) => &product.samples);
+      assert_eq!(product.len(), 3000 * 16000 / 22050);
+      assert!(product.iter().any(|&x| x != 0.0));
+   }
+}
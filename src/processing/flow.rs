@@ -1,19 +1,26 @@
-use super::{InputNo, OutputNo, Type, element, flow_store::FlowStore, prim_element::PrimElement};
+use super::{InputNo, OutputNo, Type, PrimType, Value, Port, element, flow_store::{FlowStore, FlowId}, prim_element::{PrimElement, cast_value}, template::{Param, Substitution}};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use linear_map::LinearMap;
 use daggy::stable_dag::{StableDag, NodeIndex, EdgeIndex, Walker};
-use daggy::petgraph::{visit::IntoNodeReferences, stable_graph::NodeReferences};
+use daggy::petgraph::visit::{IntoNodeReferences, IntoEdgeReferences, EdgeRef};
 
 pub type NodeIx = NodeIndex<u32>;
 pub type EdgeIx = EdgeIndex<u32>;
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct FeedbackIx(pub(super) u32);
+
 #[derive(Clone, Debug)]
 pub struct Flow {
    graph: StableDag<Node, Edge>,
    visit_order: Vec<NodeIx>,
    input_types: BTreeMap<InputNo, Type>,
    output_types: BTreeMap<OutputNo, Type>,
+   feedback_edges: BTreeMap<FeedbackIx, (NodeIx, NodeIx, Edge)>,
+   converters: BTreeSet<NodeIx>,
+   defaults: BTreeMap<(NodeIx, InputNo), Value>,
+   params: Vec<Param>,
 }
 
 impl Flow {
@@ -23,28 +30,47 @@ impl Flow {
          visit_order: Vec::new(),
          input_types: BTreeMap::new(),
          output_types: BTreeMap::new(),
+         feedback_edges: BTreeMap::new(),
+         converters: BTreeSet::new(),
+         defaults: BTreeMap::new(),
+         params: Vec::new(),
       }
    }
 
-   pub(super) fn add_element(&mut self, element: element::Element) -> NodeIx {
-      self.graph.add_node(Node::Element(element))
+   pub(super) fn add_node(&mut self, node: Node) -> NodeIx {
+      match node {
+         Node::Input{no, ty, ..} => assert!( self.input_types.insert(no, ty).is_none() ),
+         Node::Output{no, ty, ..} => assert!( self.output_types.insert(no, ty).is_none() ),
+         _ => {}
+      }
+      let node_ix = self.graph.add_node(node);
+      self.update_visit_order();
+      node_ix
    }
 
-   pub(super) fn add_input(&mut self, ty: Type) -> (InputNo, NodeIx) {
-      let no = next_no(&self.input_types);
-      self.input_types.insert(no, ty);
-      ( no, self.graph.add_node(Node::Input{no, ty}) )
-   }
+   pub(super) fn add_nodes_at<I: IntoIterator<Item = (NodeIx, Node)>>(&mut self, nodes: I) {
+      assert_eq!(self.graph.node_count(), 0);
 
-   pub(super) fn add_output(&mut self, ty: Type) -> (OutputNo, NodeIx) {
-      let no = next_no(&self.output_types);
-      self.output_types.insert(no, ty);
-      ( no, self.graph.add_node(Node::Output{no, ty}) )
+      let mut placeholders = Vec::new();
+      for (node_ix, node) in nodes {
+         assert!(node_ix.index() >= self.graph.node_count());
+         while self.graph.node_count() < node_ix.index() {
+            placeholders.push( self.graph.add_node(node.clone()) );
+         }
+         assert_eq!(self.add_node(node), node_ix);
+      }
+      placeholders.into_iter().rev().for_each(|ix| { self.graph.remove_node(ix); });
+      self.update_visit_order();
    }
 
+   pub(super) fn next_input_no(&self) -> InputNo { next_no(&self.input_types) }
+
+   pub(super) fn next_output_no(&self) -> OutputNo { next_no(&self.output_types) }
+
    pub(super) fn remove_node(&mut self, node_ix: NodeIx) -> Option<Node> {
       self.graph.remove_node(node_ix).map(|node| {
          self.update_visit_order();
+         self.converters.remove(&node_ix);
          match node {
             Node::Input{no, ..} => {self.input_types.remove(&no);}
             Node::Output{no, ..} => {self.output_types.remove(&no);}
@@ -57,11 +83,11 @@ impl Flow {
    pub(super) fn add_edge(&mut self, source_node: NodeIx, output_no: OutputNo, target_node: NodeIx, input_no: InputNo, store: &FlowStore)
       -> Result<(EdgeIx, Type), EdgeError>
    {
-      let ty = self.graph[source_node].output_types(store)[&output_no];
-      if self.graph[target_node].input_types(store)[&input_no] != ty {
-         return Err(EdgeError::TypeMismatch)
+      let ty = self.edge_type(source_node, output_no, target_node, input_no, store)?;
+      // The DAG only looks for paths back to the source, so it misses edges to the node itself.
+      if source_node == target_node {
+         return Err(EdgeError::WouldCycle)
       }
-
       self.graph.add_edge(source_node, target_node, Edge{output_no, input_no, ty})
          .map(|edge| {
             self.update_visit_order();
@@ -70,20 +96,204 @@ impl Flow {
          .map_err(|_| EdgeError::WouldCycle)
    }
 
-   pub(super) fn remove_edge(&mut self, edge: EdgeIx) -> bool {
-      if self.graph.remove_edge(edge).is_some() {
+   pub(super) fn remove_edge(&mut self, edge: EdgeIx) -> Option<(NodeIx, OutputNo, NodeIx, InputNo)> {
+      let (source, target) = self.graph.edge_endpoints(edge)?;
+      self.graph.remove_edge(edge).map(|Edge{output_no, input_no, ..}| {
          self.update_visit_order();
-         true
+         (source, output_no, target, input_no)
+      })
+   }
+
+   // Feedback edges live outside the DAG, so they may close cycles. Their target reads what
+   // the source produced in the previous block.
+   pub(super) fn add_feedback_edge(&mut self, source_node: NodeIx, output_no: OutputNo, target_node: NodeIx, input_no: InputNo, store: &FlowStore)
+      -> Result<(FeedbackIx, Type), EdgeError>
+   {
+      let feedback_ix = self.feedback_edges.keys().last().map(|ix| FeedbackIx(ix.0 + 1)).unwrap_or(FeedbackIx(0));
+      let ty = self.insert_feedback_edge(feedback_ix, source_node, output_no, target_node, input_no, store)?;
+      Ok((feedback_ix, ty))
+   }
+
+   pub(super) fn insert_feedback_edge(
+      &mut self, feedback_ix: FeedbackIx, source_node: NodeIx, output_no: OutputNo, target_node: NodeIx, input_no: InputNo, store: &FlowStore
+   ) -> Result<Type, EdgeError>
+   {
+      let ty = self.edge_type(source_node, output_no, target_node, input_no, store)?;
+      assert!( self.feedback_edges.insert(feedback_ix, (source_node, target_node, Edge{output_no, input_no, ty})).is_none() );
+      Ok(ty)
+   }
+
+   pub(super) fn remove_feedback_edge(&mut self, feedback_ix: FeedbackIx) -> Option<(NodeIx, OutputNo, NodeIx, InputNo)> {
+      self.feedback_edges.remove(&feedback_ix).map(|(source, target, Edge{output_no, input_no, ..})| (source, output_no, target, input_no))
+   }
+
+   pub(super) fn edge_type(&self, source_node: NodeIx, output_no: OutputNo, target_node: NodeIx, input_no: InputNo, store: &FlowStore)
+      -> Result<Type, EdgeError>
+   {
+      let source = self.graph.node_weight(source_node).ok_or(EdgeError::UnknownNode(source_node))?;
+      let target = self.graph.node_weight(target_node).ok_or(EdgeError::UnknownNode(target_node))?;
+      let actual = *source.output_types(store).get(&output_no).ok_or(EdgeError::UnknownOutput{node_ix: source_node, output_no})?;
+      let expected = *target.input_types(store).get(&input_no).ok_or(EdgeError::UnknownInput{node_ix: target_node, input_no})?;
+      if expected != actual {
+         return Err(EdgeError::TypeMismatch{expected, actual})
       }
-      else { false }
+      Ok(actual)
    }
 
-   pub(super) fn prim_elements<'a>(&'a self, flow_store: &'a FlowStore) -> impl Iterator<Item=PrimElement> + 'a {
-      self.graph.node_references().flat_map(move |(_,node)| match node {
-         Node::Element(element::Element::Prim(pe_id)) => Box::new(std::iter::once(*pe_id)) as Box<dyn Iterator<Item=PrimElement>>,
-         Node::Element(element::Element::Flow(flow_id)) => Box::new(flow_store[*flow_id].prim_elements(flow_store)),
-         _ => Box::new(std::iter::empty()),
-      })
+   // The element `target_node` turns into when the edge is added, for a type-generic
+   // element that has no edges or defaults pinning its type yet.
+   pub(super) fn inferred_element(&self, source_node: NodeIx, output_no: OutputNo, target_node: NodeIx, input_no: InputNo, store: &FlowStore)
+      -> Option<element::Element>
+   {
+      let actual = *self.graph.node_weight(source_node)?.output_types(store).get(&output_no)?;
+      let element = option_match!(self.graph.node_weight(target_node)?, Node::Element(element) => element)?;
+      let pinned = self.graph.parents(target_node).iter(&self.graph).next().is_some()
+         || self.graph.children(target_node).iter(&self.graph).next().is_some()
+         || self.node_feedback_edges(target_node).next().is_some()
+         || self.node_defaults(target_node).next().is_some();
+      element.infer(input_no, actual).filter(|inferred| !pinned && inferred != element)
+   }
+
+   // Only for primitive elements, which neither refer to flows nor carry edges yet.
+   pub(super) fn set_element(&mut self, node_ix: NodeIx, element: element::Element) -> element::Element {
+      let node = std::mem::replace(&mut self.graph[node_ix], Node::Element(element));
+      unwrap_match!(node, Node::Element(old) => old)
+   }
+
+   pub(super) fn would_cycle(&self, source_node: NodeIx, target_node: NodeIx) -> bool {
+      daggy::petgraph::algo::has_path_connecting(self.graph.graph(), target_node, source_node, None)
+   }
+
+   // Converter nodes are inserted by `Store::add_edge` to join mismatched types, and
+   // editors may hide them.
+   pub(super) fn set_converter(&mut self, node_ix: NodeIx, converter: bool) -> bool {
+      match converter {
+         true => !self.converters.insert(node_ix),
+         false => self.converters.remove(&node_ix),
+      }
+   }
+
+   pub fn is_converter(&self, node_ix: NodeIx) -> bool { self.converters.contains(&node_ix) }
+
+   pub fn converters<'a>(&'a self) -> impl Iterator<Item = NodeIx> + 'a { self.converters.iter().copied() }
+
+   // Values fed into unconnected sampled inputs, overriding the defaults of their ports.
+   pub(super) fn set_default(&mut self, node_ix: NodeIx, input_no: InputNo, value: Option<Value>) -> Option<Value> {
+      match value {
+         Some(value) => self.defaults.insert((node_ix, input_no), value),
+         None => self.defaults.remove(&(node_ix, input_no)),
+      }
+   }
+
+   // Clearing a default (`None`) needs only the input to exist.
+   pub(super) fn check_default(&self, node_ix: NodeIx, input_no: InputNo, value: Option<Value>, store: &FlowStore) -> Result<(), DefaultError> {
+      let node = self.graph.node_weight(node_ix).ok_or(DefaultError::UnknownNode(node_ix))?;
+      let expected = *node.input_types(store).get(&input_no).ok_or(DefaultError::UnknownInput{node_ix, input_no})?;
+      match (expected, value) {
+         (_, None) => Ok(()),
+         (Type::Sampled{ty, ..}, Some(value)) | (Type::Bus{ty, ..}, Some(value)) if ty == value.Type() => Ok(()),
+         (_, Some(value)) => Err(DefaultError::TypeMismatch{expected, actual: value.Type()}),
+      }
+   }
+
+   pub fn default(&self, node_ix: NodeIx, input_no: InputNo) -> Option<Value> {
+      self.defaults.get(&(node_ix, input_no)).copied()
+   }
+
+   pub fn defaults<'a>(&'a self) -> impl Iterator<Item = (NodeIx, InputNo, Value)> + 'a {
+      self.defaults.iter().map(|(&(node_ix, input_no), &value)| (node_ix, input_no, value))
+   }
+
+   pub fn node_defaults<'a>(&'a self, node_ix: NodeIx) -> impl Iterator<Item = (InputNo, Value)> + 'a {
+      self.defaults().filter(move |(ix, ..)| *ix == node_ix).map(|(_, input_no, value)| (input_no, value))
+   }
+
+   // The value an unconnected input is fed with, if any: the one set on the node, or else the
+   // default of its port. Values that no longer fit the port are ignored. A parameter bound to
+   // the default of an instance overrides both, cast to the type of the input.
+   pub fn input_default(&self, node_ix: NodeIx, input_no: InputNo, store: &FlowStore) -> Option<Value> {
+      self.substituted_input_default(node_ix, input_no, &Substitution::identity(), store)
+   }
+
+   pub(super) fn substituted_input_default(&self, node_ix: NodeIx, input_no: InputNo, substitution: &Substitution, store: &FlowStore)
+      -> Option<Value>
+   {
+      let node = substitution.node(node_ix, self.graph.node_weight(node_ix)?);
+      let ty = match node.input_types(store).get(&input_no)? {
+         Type::Sampled{ty, ..} | Type::Bus{ty, ..} => *ty,
+         Type::Event(_) => return None,
+      };
+      if let Some(value) = substitution.default(node_ix, input_no) {
+         return Some(if value.Type() == ty { value } else { cast_value(value, ty) })
+      }
+      let value = self.default(node_ix, input_no).or_else(|| node.input_ports(store).get(&input_no)?.default)?;
+      Some(value).filter(|value| value.Type() == ty)
+   }
+
+   // Problems that do not prevent processing, but usually indicate a mistake in the patch.
+   // Edges are type checked when added, so mismatches only appear after sub-flows change.
+   pub fn validate(&self, store: &FlowStore) -> Vec<Diagnostic> {
+      let mut diagnostics = Vec::new();
+
+      for (node_ix, node) in self.nodes() {
+         let mut connected = self.graph.parents(node_ix).iter(&self.graph).next().is_some()
+            || self.graph.children(node_ix).iter(&self.graph).next().is_some()
+            || self.node_feedback_edges(node_ix).next().is_some();
+         if !connected {
+            diagnostics.push(Diagnostic::OrphanNode(node_ix));
+            continue
+         }
+
+         for input_no in node.input_types(store).keys() {
+            connected = self.input_edges(node_ix, *input_no).next().is_some()
+               || self.input_feedback_edges_with_node(node_ix, *input_no).next().is_some()
+               || self.input_default(node_ix, *input_no, store).is_some();
+            if !connected {
+               diagnostics.push(Diagnostic::UnconnectedInput{node_ix, input_no: *input_no});
+            }
+         }
+      }
+
+      let edges = self.edges().map(|(_, source, target, edge)| (source, target, edge))
+         .chain(self.feedback_edges().map(|(_, source, target, edge)| (source, target, edge)));
+      for (source, target, edge) in edges {
+         let (output_no, input_no) = (edge.output_no, edge.input_no);
+         let output_ty = self.graph[source].output_types(store).get(&output_no).copied();
+         let input_ty = self.graph[target].input_types(store).get(&input_no).copied();
+         match (output_ty, input_ty) {
+            (Some(Type::Sampled{ty: output_ty, f_nyq: output_f_nyq}), Some(Type::Sampled{ty: input_ty, f_nyq: input_f_nyq}))
+               if output_ty == input_ty && output_f_nyq != input_f_nyq =>
+               diagnostics.push(Diagnostic::RateMismatch{source, output_no, target, input_no, output_f_nyq, input_f_nyq}),
+            (Some(Type::Bus{ty: output_ty, f_nyq: output_f_nyq, channels: output_channels}),
+             Some(Type::Bus{ty: input_ty, f_nyq: input_f_nyq, channels: input_channels}))
+               if output_ty == input_ty && output_channels == input_channels && output_f_nyq != input_f_nyq =>
+               diagnostics.push(Diagnostic::RateMismatch{source, output_no, target, input_no, output_f_nyq, input_f_nyq}),
+            (Some(actual), Some(expected)) if actual != expected =>
+               diagnostics.push(Diagnostic::TypeMismatch{source, output_no, target, input_no, expected, actual}),
+            (Some(_), Some(_)) => {}
+            _ => diagnostics.push(Diagnostic::DanglingEdge{source, output_no, target, input_no}),
+         }
+      }
+
+      diagnostics
+   }
+
+   pub fn params(&self) -> &[Param] { &self.params }
+
+   pub(super) fn set_params(&mut self, params: Vec<Param>) -> Vec<Param> { std::mem::replace(&mut self.params, params) }
+
+   pub(super) fn prim_elements(&self, flow_store: &FlowStore) -> Vec<PrimElement> {
+      self.substituted_prim_elements(&Substitution::identity(), flow_store)
+   }
+
+   fn substituted_prim_elements(&self, substitution: &Substitution, flow_store: &FlowStore) -> Vec<PrimElement> {
+      self.graph.node_references().flat_map(|(node_ix, node)| match substitution.node(node_ix, node) {
+         Node::Element(element::Element::Prim(pe_id)) | Node::Element(element::Element::PerChannel(pe_id, _)) => vec![pe_id],
+         Node::Element(element::Element::Flow(flow_id)) => flow_store[flow_id].prim_elements(flow_store),
+         Node::Element(element::Element::Template(flow_id, args)) =>
+            flow_store[flow_id].substituted_prim_elements(&Substitution::new(&flow_store[flow_id], &args), flow_store),
+         _ => Vec::new(),
+      }).collect()
    }
 
    pub fn input_types<'a>(&'a self) -> impl Iterator<Item = (InputNo, Type)> + 'a {
@@ -94,6 +304,20 @@ impl Flow {
       self.output_types.iter().map(|(k,v)| (*k,*v))
    }
 
+   pub fn input_ports<'a>(&'a self) -> impl Iterator<Item = (InputNo, Port)> + 'a {
+      self.input_types.keys().map(move |no| {
+         let name = self.nodes().find_map(|(_, node)| option_match!(node, Node::Input{no: input_no, name, ..} if input_no == no => name));
+         (*no, Port::named(name.cloned().unwrap_or_default()))
+      })
+   }
+
+   pub fn output_ports<'a>(&'a self) -> impl Iterator<Item = (OutputNo, Port)> + 'a {
+      self.output_types.keys().map(move |no| {
+         let name = self.nodes().find_map(|(_, node)| option_match!(node, Node::Output{no: output_no, name, ..} if output_no == no => name));
+         (*no, Port::named(name.cloned().unwrap_or_default()))
+      })
+   }
+
    pub fn input_nos<'a>(&'a self) -> impl Iterator<Item = InputNo> + 'a {
       self.input_types.keys().copied()
    }
@@ -106,12 +330,35 @@ impl Flow {
       self.visit_order = daggy::petgraph::algo::toposort(self.graph.graph(), None).unwrap();
    }
 
-   pub(super) fn visit_order(&self) -> impl Iterator<Item=&NodeIx> { self.visit_order.iter() }
+   pub fn visit_order(&self) -> impl Iterator<Item=&NodeIx> { self.visit_order.iter() }
 
    pub(super) fn graph(&self) -> &StableDag<Node, Edge> { &self.graph }
 
+   pub fn nodes<'a>(&'a self) -> impl Iterator<Item = (NodeIx, &'a Node)> + 'a {
+      self.graph.graph().node_references()
+   }
+
+   pub fn edges<'a>(&'a self) -> impl Iterator<Item = (EdgeIx, NodeIx, NodeIx, &'a Edge)> + 'a {
+      self.graph.graph().edge_references().map(|edge| (edge.id(), edge.source(), edge.target(), edge.weight()))
+   }
+
+   pub fn feedback_edges<'a>(&'a self) -> impl Iterator<Item = (FeedbackIx, NodeIx, NodeIx, &'a Edge)> + 'a {
+      self.feedback_edges.iter().map(|(ix, (source, target, edge))| (*ix, *source, *target, edge))
+   }
+
    pub fn node(&self, node_ix: NodeIx) -> &Node { &self.graph[node_ix] }
 
+   pub fn get_node(&self, node_ix: NodeIx) -> Option<&Node> { self.graph.node_weight(node_ix) }
+
+   pub fn edge(&self, edge_ix: EdgeIx) -> Option<(NodeIx, NodeIx, &Edge)> {
+      let (source, target) = self.graph.edge_endpoints(edge_ix)?;
+      Some((source, target, &self.graph[edge_ix]))
+   }
+
+   pub fn references<'a>(&'a self) -> impl Iterator<Item = FlowId> + 'a {
+      self.nodes().filter_map(|(_, node)| option_match!(node, Node::Element(element) => element.flow_id()).flatten())
+   }
+
    pub fn input_edges_with_node<'a>(&'a self, node_ix: NodeIx, input_no: InputNo) -> impl Iterator<Item = (EdgeIx, NodeIx)> + 'a {
       self.graph.parents(node_ix).iter(&self.graph).filter(move |(edge,_)| self.graph[*edge].input_no == input_no)
    }
@@ -127,6 +374,17 @@ impl Flow {
    pub fn output_edges<'a>(&'a self, node_ix: NodeIx, output_no: OutputNo) -> impl Iterator<Item = EdgeIx> + 'a {
       self.output_edges_with_node(node_ix, output_no).map(|(edge_ix,_)| edge_ix)
    }
+
+   pub fn input_feedback_edges_with_node<'a>(&'a self, node_ix: NodeIx, input_no: InputNo)
+      -> impl Iterator<Item = (FeedbackIx, NodeIx, OutputNo)> + 'a
+   {
+      self.feedback_edges().filter(move |(_, _, target, edge)| *target == node_ix && edge.input_no == input_no)
+         .map(|(ix, source, _, edge)| (ix, source, edge.output_no))
+   }
+
+   pub fn node_feedback_edges<'a>(&'a self, node_ix: NodeIx) -> impl Iterator<Item = FeedbackIx> + 'a {
+      self.feedback_edges().filter(move |(_, source, target, _)| *source == node_ix || *target == node_ix).map(|(ix, ..)| ix)
+   }
 }
 
 
@@ -147,14 +405,35 @@ fn next_no<T: From<u32> + Into<u32> + Ord + Copy>(types: &BTreeMap<T, Type>) ->
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum EdgeError {
    WouldCycle,
-   TypeMismatch,
+   UnknownFlow(FlowId),
+   UnknownNode(NodeIx),
+   UnknownOutput{node_ix: NodeIx, output_no: OutputNo},
+   UnknownInput{node_ix: NodeIx, input_no: InputNo},
+   TypeMismatch{expected: Type, actual: Type},
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DefaultError {
+   UnknownFlow(FlowId),
+   UnknownNode(NodeIx),
+   UnknownInput{node_ix: NodeIx, input_no: InputNo},
+   TypeMismatch{expected: Type, actual: PrimType},
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Diagnostic {
+   OrphanNode(NodeIx),
+   UnconnectedInput{node_ix: NodeIx, input_no: InputNo},
+   DanglingEdge{source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo},
+   TypeMismatch{source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo, expected: Type, actual: Type},
+   RateMismatch{source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo, output_f_nyq: u64, input_f_nyq: u64},
 }
 
 #[derive(Clone, Debug)]
 pub enum Node {
    Element(element::Element),
-   Input{no: InputNo, ty: Type},
-   Output{no: OutputNo, ty: Type},
+   Input{no: InputNo, ty: Type, name: String},
+   Output{no: OutputNo, ty: Type, name: String},
 }
 impl Node {
    pub fn input_types(&self, store: &FlowStore) -> LinearMap<InputNo, Type> {
@@ -172,6 +451,22 @@ impl Node {
          Node::Output{..} => LinearMap::new(),
       }
    }
+
+   pub fn input_ports(&self, store: &FlowStore) -> LinearMap<InputNo, Port> {
+      match self {
+         Node::Element(e) => e.input_ports(store),
+         Node::Input{..} => LinearMap::new(),
+         Node::Output{name, ..} => std::iter::once((InputNo(0), Port::named(name.clone()))).collect(),
+      }
+   }
+
+   pub fn output_ports(&self, store: &FlowStore) -> LinearMap<OutputNo, Port> {
+      match self {
+         Node::Element(e) => e.output_ports(store),
+         Node::Input{name, ..} => std::iter::once((OutputNo(0), Port::named(name.clone()))).collect(),
+         Node::Output{..} => LinearMap::new(),
+      }
+   }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -179,4 +474,9 @@ pub struct Edge {
    output_no: OutputNo,
    input_no: InputNo,
    ty: Type,
+}
+impl Edge {
+   pub fn output_no(&self) -> OutputNo { self.output_no }
+   pub fn input_no(&self) -> InputNo { self.input_no }
+   pub fn ty(&self) -> Type { self.ty }
 }
\ No newline at end of file
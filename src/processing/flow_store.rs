@@ -1,15 +1,103 @@
-use super::flow;
+use super::{Type, OutputNo, InputNo, flow::{self, Node, NodeIx}, element::Element, template::{Arg, Param, Binding, Substitution}};
 
 use intmap::IntMap;
+use std::collections::BTreeMap;
 
+// Keeps count of the `Element::Flow` and `Element::Template` nodes that refer from one flow to another, so flows in
+// use are not removed and no flow ends up containing itself.
 #[derive(Clone, Debug)]
 pub struct FlowStore {
    flows: IntMap<flow::Flow>,
    next_id: u64,
+   references: BTreeMap<(FlowId, FlowId), usize>,
 }
 impl FlowStore {
    pub(super) fn new() -> FlowStore {
-      Self{flows: IntMap::new(), next_id: 0}
+      Self{flows: IntMap::new(), next_id: 0, references: BTreeMap::new()}
+   }
+
+   pub fn ids(&self) -> impl Iterator<Item = FlowId> {
+      let mut ids: Vec<_> = self.flows.keys().map(|id| FlowId(*id)).collect();
+      ids.sort();
+      ids.into_iter()
+   }
+
+   pub fn iter<'a>(&'a self) -> impl Iterator<Item = (FlowId, &'a flow::Flow)> + 'a {
+      self.ids().map(move |id| (id, &self[id]))
+   }
+
+   pub fn referencing<'a>(&'a self, id: FlowId) -> impl Iterator<Item = FlowId> + 'a {
+      self.references.range((id, FlowId(0))..=(id, FlowId(u64::MAX))).map(|(&(_, user), _)| user)
+   }
+
+   pub fn is_referenced(&self, id: FlowId) -> bool { self.referencing(id).next().is_some() }
+
+   // Whether `flow_id` may get a node running `sub_flow_id`.
+   pub fn check_reference(&self, flow_id: FlowId, sub_flow_id: FlowId) -> Result<(), FlowError> {
+      self.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      self.get(sub_flow_id).ok_or(FlowError::UnknownFlow(sub_flow_id))?;
+      if self.contains(sub_flow_id, flow_id) {
+         return Err(FlowError::Recursive{flow_id, sub_flow_id})
+      }
+      Ok(())
+   }
+
+   // Whether `args` fit the parameters of `flow_id`, and every edge of the instance, as well
+   // as of the templates it instantiates in turn, connects ports of the same type.
+   pub fn check_instance(&self, flow_id: FlowId, args: &[Arg]) -> Result<(), FlowError> {
+      let flow = self.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      if !Substitution::check(flow, args) {
+         return Err(FlowError::Arguments{flow_id, params: flow.params().to_vec()})
+      }
+      let substitution = Substitution::new(flow, args);
+      for (node_ix, node) in flow.nodes() {
+         if let Node::Element(Element::Template(sub_flow_id, args)) = substitution.node(node_ix, node) {
+            self.check_instance(sub_flow_id, &args)?;
+         }
+      }
+      let edges = flow.edges().map(|(_, source, target, edge)| (source, target, edge))
+         .chain(flow.feedback_edges().map(|(_, source, target, edge)| (source, target, edge)));
+      for (source, target, edge) in edges {
+         let (output_no, input_no) = (edge.output_no(), edge.input_no());
+         let actual = substitution.node(source, flow.node(source)).output_types(self).get(&output_no).copied();
+         let expected = substitution.node(target, flow.node(target)).input_types(self).get(&input_no).copied();
+         if let (Some(actual), Some(expected)) = (actual, expected) {
+            if actual != expected {
+               return Err(FlowError::TypeMismatch{flow_id, source, output_no, target, input_no, expected, actual})
+            }
+         }
+      }
+      Ok(())
+   }
+
+   // Whether every parameter is bound to places of the flow that fit it, no place is bound
+   // twice, and no two parameters share a name.
+   pub(super) fn check_params(&self, flow_id: FlowId, params: &[Param]) -> Result<(), FlowError> {
+      let flow = self.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      if let Some(param) = (0..params.len())
+         .find(|&ix| !params[ix].name.is_empty() && params[..ix].iter().any(|other| other.name == params[ix].name))
+      {
+         return Err(FlowError::DuplicateParam{flow_id, param})
+      }
+      let mut bound = Vec::new();
+      for (param, Param{default, bindings, ..}) in params.iter().enumerate() {
+         for &binding in bindings {
+            let place = match binding {
+               Binding::Field(node_ix, n) => (node_ix, Some(default.kind()), n),
+               Binding::Default(node_ix, input_no) => (node_ix, None, input_no.0 as usize),
+            };
+            if !binding.check(flow, *default, self) || bound.contains(&place) {
+               return Err(FlowError::Binding{flow_id, param, binding})
+            }
+            bound.push(place);
+         }
+      }
+      Ok(())
+   }
+
+   // Whether running `flow_id` runs `target`, directly or through nested flows.
+   fn contains(&self, flow_id: FlowId, target: FlowId) -> bool {
+      flow_id == target || self[flow_id].references().any(|sub_flow_id| self.contains(sub_flow_id, target))
    }
 
    pub fn get(&self, id: FlowId) -> Option<&flow::Flow> { self.flows.get(id.0) }
@@ -21,7 +109,50 @@ impl FlowStore {
       FlowId(next_id)
    }
 
-   pub(super) fn remove(&mut self, id: FlowId) -> flow::Flow { self.flows.remove(id.0).unwrap() }
+   pub(super) fn insert(&mut self, id: FlowId, flow: flow::Flow) {
+      flow.references().for_each(|sub_id| self.reference(id, sub_id, true));
+      assert!( self.flows.insert(id.0, flow) );
+   }
+
+   pub(super) fn remove(&mut self, id: FlowId) -> flow::Flow {
+      let flow = self.flows.remove(id.0).unwrap();
+      flow.references().for_each(|sub_id| self.reference(id, sub_id, false));
+      flow
+   }
+
+   pub(super) fn add_node(&mut self, id: FlowId, node: Node) -> NodeIx {
+      if let Some(sub_id) = node_flow_id(&node) {
+         self.reference(id, sub_id, true);
+      }
+      self[id].add_node(node)
+   }
+
+   pub(super) fn add_nodes_at<I: IntoIterator<Item = (NodeIx, Node)>>(&mut self, id: FlowId, nodes: I) {
+      let nodes: Vec<_> = nodes.into_iter().collect();
+      nodes.iter().filter_map(|(_, node)| node_flow_id(node)).for_each(|sub_id| self.reference(id, sub_id, true));
+      self[id].add_nodes_at(nodes)
+   }
+
+   pub(super) fn remove_node(&mut self, id: FlowId, node_ix: NodeIx) -> Option<Node> {
+      let node = self[id].remove_node(node_ix)?;
+      if let Some(sub_id) = node_flow_id(&node) {
+         self.reference(id, sub_id, false);
+      }
+      Some(node)
+   }
+
+   fn reference(&mut self, id: FlowId, sub_id: FlowId, added: bool) {
+      let count = self.references.entry((sub_id, id)).or_insert(0);
+      match added {
+         true => *count += 1,
+         false => {
+            *count -= 1;
+            if *count == 0 {
+               self.references.remove(&(sub_id, id));
+            }
+         }
+      }
+   }
 
    pub(super) fn alter<T, F: FnOnce(&Self, &mut flow::Flow) -> T>(&mut self, id: FlowId, f: F) -> T {
       let mut flow = self.flows.remove(id.0).unwrap();
@@ -42,6 +173,10 @@ impl FlowStore {
    }
 }
 
+fn node_flow_id(node: &Node) -> Option<FlowId> {
+   option_match!(node, Node::Element(element) => element.flow_id()).flatten()
+}
+
 impl std::ops::Index<FlowId> for FlowStore {
    type Output = flow::Flow;
    fn index(&self, id: FlowId) -> &flow::Flow { self.get(id).unwrap() }
@@ -52,4 +187,16 @@ impl std::ops::IndexMut<FlowId> for FlowStore {
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
-pub struct FlowId(pub(super) u64);
\ No newline at end of file
+pub struct FlowId(pub(super) u64);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FlowError {
+   UnknownFlow(FlowId),
+   UnknownNode{flow_id: FlowId, node_ix: NodeIx},
+   Recursive{flow_id: FlowId, sub_flow_id: FlowId},
+   InUse{flow_id: FlowId, users: Vec<FlowId>},
+   Arguments{flow_id: FlowId, params: Vec<Param>},
+   TypeMismatch{flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo, expected: Type, actual: Type},
+   DuplicateParam{flow_id: FlowId, param: usize},
+   Binding{flow_id: FlowId, param: usize, binding: Binding},
+}
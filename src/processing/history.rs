@@ -0,0 +1,89 @@
+use super::{
+   flow::{Flow, Node, NodeIx, EdgeIx, FeedbackIx},
+   flow_store::FlowId,
+   processor::{Processor, NodeProcessor},
+   template::Param,
+   element::Element,
+   InputNo, OutputNo, Value,
+};
+
+// A single primitive edit of a `Store`. Applying an edit yields the edit that reverts it.
+// Replaying inverses strictly in reverse order reproduces the original `NodeIx`/`EdgeIx`,
+// since the underlying `StableDag` reuses vacated slots last-in first-out.
+pub(super) enum Edit {
+   AddFlow(Option<Box<(FlowId, Flow, Processor)>>),
+   RemoveFlow(FlowId),
+   AddNode{flow_id: FlowId, node: Node, node_processors: Option<Vec<NodeProcessor>>},
+   RemoveNode{flow_id: FlowId, node_ix: NodeIx},
+   AddEdge{flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo},
+   RemoveEdge{flow_id: FlowId, edge_ix: EdgeIx},
+   AddFeedbackEdge{flow_id: FlowId, feedback_ix: FeedbackIx, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo},
+   RemoveFeedbackEdge{flow_id: FlowId, feedback_ix: FeedbackIx},
+   SetConverter{flow_id: FlowId, node_ix: NodeIx, converter: bool},
+   SetElement{flow_id: FlowId, node_ix: NodeIx, element: Element},
+   SetParams{flow_id: FlowId, params: Vec<Param>},
+   SetDefault{flow_id: FlowId, node_ix: NodeIx, input_no: InputNo, value: Option<Value>},
+}
+
+pub(super) struct History {
+   undo: Vec<Vec<Edit>>,
+   redo: Vec<Vec<Edit>>,
+   transaction: Vec<Edit>,
+   depth: u32,
+}
+impl History {
+   pub(super) fn new() -> Self {
+      Self{undo: Vec::new(), redo: Vec::new(), transaction: Vec::new(), depth: 0}
+   }
+
+   pub(super) fn record(&mut self, inverse: Edit) {
+      self.redo.clear();
+      self.transaction.push(inverse);
+      if self.depth == 0 {
+         self.close();
+      }
+   }
+
+   pub(super) fn begin(&mut self) {
+      self.depth += 1;
+   }
+
+   pub(super) fn commit(&mut self) {
+      assert!(self.depth > 0);
+      self.depth -= 1;
+      if self.depth == 0 {
+         self.close();
+      }
+   }
+
+   pub(super) fn in_transaction(&self) -> bool { self.depth > 0 }
+
+   // How far the open transaction has got, to roll back to.
+   pub(super) fn mark(&self) -> usize { self.transaction.len() }
+
+   // Takes back the inverses recorded since `mark`, to be applied in reverse order.
+   pub(super) fn rollback(&mut self, mark: usize) -> Vec<Edit> { self.transaction.split_off(mark) }
+
+   pub(super) fn can_undo(&self) -> bool { !self.undo.is_empty() }
+
+   pub(super) fn can_redo(&self) -> bool { !self.redo.is_empty() }
+
+   pub(super) fn pop_undo(&mut self) -> Option<Vec<Edit>> { self.undo.pop() }
+
+   pub(super) fn pop_redo(&mut self) -> Option<Vec<Edit>> { self.redo.pop() }
+
+   pub(super) fn push_undo(&mut self, group: Vec<Edit>) { self.undo.push(group) }
+
+   pub(super) fn push_redo(&mut self, group: Vec<Edit>) { self.redo.push(group) }
+
+   pub(super) fn clear(&mut self) {
+      self.undo.clear();
+      self.redo.clear();
+   }
+
+   fn close(&mut self) {
+      if !self.transaction.is_empty() {
+         self.undo.push(std::mem::take(&mut self.transaction));
+      }
+   }
+}
@@ -14,11 +14,18 @@ pub mod flow_store;
 pub mod store;
 pub mod processor;
 pub mod prim_element;
+pub mod template;
+pub mod patch;
+pub mod engine;
+mod history;
+mod simd;
 
-pub use store::Store;
+pub use store::{Store, EdgeMode};
 pub use flow_store::FlowId;
 pub use processor::Buffer;
 
+use std::borrow::Cow;
+
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct InputNo(u32);
@@ -43,9 +50,35 @@ impl Into<u32> for OutputNo {
 pub enum Type {
    Sampled{ty: PrimType, f_nyq: u64},
    Event(PrimType),
+   Bus{ty: PrimType, f_nyq: u64, channels: u32},
 }
+impl Type {
+   // A bus carries `channels` sampled streams side by side, every other type a single one.
+   pub fn channels(&self) -> u32 {
+      match self {
+         Type::Bus{channels, ..} => *channels,
+         _ => 1,
+      }
+   }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+   // The type of each channel.
+   pub fn channel(&self) -> Type {
+      match *self {
+         Type::Bus{ty, f_nyq, ..} => Type::Sampled{ty, f_nyq},
+         ty => ty,
+      }
+   }
+
+   // A bus of `channels` sampled streams of this type. Events stay shared by all channels.
+   pub fn bus(&self, channels: u32) -> Type {
+      match *self {
+         Type::Sampled{ty, f_nyq} | Type::Bus{ty, f_nyq, ..} => Type::Bus{ty, f_nyq, channels},
+         ty => ty,
+      }
+   }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum PrimType {
    F32,
    C32,
@@ -53,6 +86,28 @@ pub enum PrimType {
    I32,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unit {
+   None,
+   Hz,
+   Db,
+   Seconds,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Port {
+   pub name: Cow<'static, str>,
+   pub description: Cow<'static, str>,
+   pub unit: Unit,
+   pub range: Option<(f64, f64)>,
+   pub default: Option<Value>,
+}
+impl Port {
+   pub fn named<S: Into<Cow<'static, str>>>(name: S) -> Self {
+      Self{name: name.into(), description: Cow::Borrowed(""), unit: Unit::None, range: None, default: None}
+   }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Value {
    F32(eq_float::F32),
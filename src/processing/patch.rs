@@ -0,0 +1,545 @@
+use super::{
+   Store, EdgeMode, FlowId, Type, PrimType, Value, InputNo, OutputNo,
+   flow::{Node, NodeIx, EdgeError, DefaultError},
+   flow_store::FlowError,
+   element::Element,
+   prim_element::{PrimElement, BinaryOp, UnaryOp},
+   template::{Arg, Param, Binding},
+};
+
+use nom::{
+   IResult,
+   branch::alt,
+   bytes::complete::{escaped_transform, is_not, tag, take_till1},
+   character::complete::{char, digit1, space1},
+   combinator::{all_consuming, map, map_res, opt, recognize},
+   multi::many0,
+   sequence::{delimited, pair, preceded, tuple},
+};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const VERSION: u32 = 1;
+const MAGIC: &str = "orgument-patch";
+// Bounds on what a patch may ask for, so that a corrupt file is refused rather than allocated.
+const MAX_NODE_IX: usize = 1 << 16;
+const MAX_PORTS: u32 = 1 << 10;
+
+#[derive(Debug)]
+pub enum PatchError {
+   Io(io::Error),
+   Syntax{line: usize},
+   UnsupportedVersion(u32),
+   MissingFlow{line: usize},
+   DuplicateFlow{line: usize, flow_id: FlowId},
+   NodeOrder{line: usize, node_ix: NodeIx},
+   NodeLimit{line: usize, node_ix: NodeIx},
+   PortLimit{line: usize, node_ix: NodeIx},
+   DuplicateInput{line: usize, no: InputNo},
+   DuplicateOutput{line: usize, no: OutputNo},
+   UnknownFlow{line: usize, flow_id: FlowId},
+   RecursiveFlow{line: usize, flow_id: FlowId},
+   Flow{line: usize, error: FlowError},
+   UnknownNode{line: usize, node_ix: NodeIx},
+   Edge{line: usize, error: EdgeError},
+   Default{line: usize, error: DefaultError},
+}
+impl From<io::Error> for PatchError {
+   fn from(e: io::Error) -> Self { PatchError::Io(e) }
+}
+
+pub fn save<P: AsRef<Path>>(store: &Store, path: P) -> io::Result<()> {
+   write(store, io::BufWriter::new(std::fs::File::create(path)?))
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Store, PatchError> {
+   read(std::fs::File::open(path)?)
+}
+
+pub fn write<W: Write>(store: &Store, mut writer: W) -> io::Result<()> {
+   writeln!(writer, "{} {}", MAGIC, VERSION)?;
+
+   let flow_store = store.flow_store();
+   for flow_id in flow_store.ids() {
+      let flow = &flow_store[flow_id];
+      writeln!(writer)?;
+      writeln!(writer, "flow {}", flow_id.0)?;
+      for param in flow.params() {
+         writeln!(writer, "param{} {}{}", fmt_name(&param.name), fmt_arg(param.default),
+            param.bindings.iter().map(|binding| format!(" {}", fmt_binding(*binding))).collect::<String>())?;
+      }
+      for (node_ix, node) in flow.nodes() {
+         writeln!(writer, "node {} {}", node_ix.index(), fmt_node(node))?;
+      }
+      for node_ix in flow.converters() {
+         writeln!(writer, "converter {}", node_ix.index())?;
+      }
+      for (node_ix, input_no, value) in flow.defaults() {
+         writeln!(writer, "default {} {} {}", node_ix.index(), Into::<u32>::into(input_no), fmt_value(value))?;
+      }
+      for (_, source, target, edge) in flow.edges() {
+         let (output_no, input_no): (u32, u32) = (edge.output_no().into(), edge.input_no().into());
+         writeln!(writer, "edge {} {} {} {}", source.index(), output_no, target.index(), input_no)?;
+      }
+      for (_, source, target, edge) in flow.feedback_edges() {
+         let (output_no, input_no): (u32, u32) = (edge.output_no().into(), edge.input_no().into());
+         writeln!(writer, "feedback {} {} {} {}", source.index(), output_no, target.index(), input_no)?;
+      }
+   }
+   writer.flush()
+}
+
+pub fn read<R: Read>(mut reader: R) -> Result<Store, PatchError> {
+   let mut text = String::new();
+   reader.read_to_string(&mut text)?;
+
+   let mut lines = text.lines().enumerate()
+      .map(|(n, line)| (n + 1, line.trim()))
+      .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+   match lines.next() {
+      Some((n, line)) => match all_consuming(header)(line) {
+         Ok((_, VERSION)) => {}
+         Ok((_, version)) => return Err(PatchError::UnsupportedVersion(version)),
+         Err(_) => return Err(PatchError::Syntax{line: n}),
+      }
+      None => return Err(PatchError::Syntax{line: 1}),
+   }
+
+   let mut sections: Vec<FlowSection> = Vec::new();
+   for (n, line) in lines {
+      let line = all_consuming(statement)(line).map_err(|_| PatchError::Syntax{line: n})?.1;
+      if let Line::Flow(flow_id) = line {
+         sections.push(FlowSection{line: n, flow_id, params: Vec::new(), nodes: Vec::new(), converters: Vec::new(), defaults: Vec::new(), edges: Vec::new()});
+         continue
+      }
+
+      let section = sections.last_mut().ok_or(PatchError::MissingFlow{line: n})?;
+      match line {
+         Line::Param(param) => section.params.push(param),
+         Line::Node(node_ix, node) => {
+            if matches!(section.nodes.last(), Some((_, last, _)) if *last >= node_ix) {
+               return Err(PatchError::NodeOrder{line: n, node_ix})
+            }
+            if node_ix.index() >= MAX_NODE_IX {
+               return Err(PatchError::NodeLimit{line: n, node_ix})
+            }
+            if ports(&node) > MAX_PORTS {
+               return Err(PatchError::PortLimit{line: n, node_ix})
+            }
+            match &node {
+               Node::Input{no, ..} if section.nodes.iter().any(|(_, _, node)| matches!(node, Node::Input{no: other, ..} if other == no)) =>
+                  return Err(PatchError::DuplicateInput{line: n, no: *no}),
+               Node::Output{no, ..} if section.nodes.iter().any(|(_, _, node)| matches!(node, Node::Output{no: other, ..} if other == no)) =>
+                  return Err(PatchError::DuplicateOutput{line: n, no: *no}),
+               _ => {}
+            }
+            section.nodes.push((n, node_ix, node));
+         }
+         Line::Converter(node_ix) => section.converters.push((n, node_ix)),
+         Line::Default(node_ix, input_no, value) => section.defaults.push((n, node_ix, input_no, value)),
+         Line::Edge{feedback, source, output_no, target, input_no} => section.edges.push((n, feedback, source, output_no, target, input_no)),
+         Line::Flow(_) => unreachable!(),
+      }
+   }
+
+   let mut store = Store::new();
+   for section in &sections {
+      if store.flow_store().get(section.flow_id).is_some() {
+         return Err(PatchError::DuplicateFlow{line: section.line, flow_id: section.flow_id})
+      }
+      store.insert_flow(section.flow_id);
+   }
+   for section in &sections {
+      for (n, _, node) in &section.nodes {
+         if let Some(sub_flow_id) = option_match!(node, Node::Element(element) => element.flow_id()).flatten() {
+            match store.flow_store().check_reference(section.flow_id, sub_flow_id) {
+               Ok(()) => {}
+               Err(FlowError::Recursive{..}) => return Err(PatchError::RecursiveFlow{line: *n, flow_id: sub_flow_id}),
+               Err(_) => return Err(PatchError::UnknownFlow{line: *n, flow_id: sub_flow_id}),
+            }
+         }
+      }
+      store.insert_nodes(section.flow_id, section.nodes.iter().map(|(_, node_ix, node)| (*node_ix, node.clone())));
+      for (n, node_ix) in &section.converters {
+         if store.flow_store()[section.flow_id].graph().node_weight(*node_ix).is_none() {
+            return Err(PatchError::UnknownNode{line: *n, node_ix: *node_ix})
+         }
+         store.set_converter(section.flow_id, *node_ix, true);
+      }
+   }
+   for section in &sections {
+      store.insert_params(section.flow_id, section.params.clone()).map_err(|error| PatchError::Flow{line: section.line, error})?;
+   }
+   for section in &sections {
+      for (n, node_ix, input_no, value) in &section.defaults {
+         store.set_default(section.flow_id, *node_ix, *input_no, Some(*value)).map_err(|error| PatchError::Default{line: *n, error})?;
+      }
+   }
+   for section in &sections {
+      for &(n, feedback, source, output_no, target, input_no) in &section.edges {
+         let result = match feedback {
+            false => store.add_edge(section.flow_id, source, output_no, target, input_no, EdgeMode::Exact).map(|_| ()),
+            true => store.add_feedback_edge(section.flow_id, source, output_no, target, input_no).map(|_| ()),
+         };
+         result.map_err(|error| PatchError::Edge{line: n, error})?;
+      }
+   }
+   // Instances are type checked once every template is complete.
+   for section in &sections {
+      for (n, _, node) in &section.nodes {
+         if let Node::Element(Element::Template(sub_flow_id, args)) = node {
+            store.flow_store().check_instance(*sub_flow_id, args).map_err(|error| PatchError::Flow{line: *n, error})?;
+         }
+      }
+   }
+   store.clear_history();
+
+   Ok(store)
+}
+
+// The number of channels or inputs `node` asks for, which processing allocates buffers for.
+fn ports(node: &Node) -> u32 {
+   let channels = |ty: &Type| option_match!(ty, Type::Bus{channels, ..} => *channels).unwrap_or(1);
+   match node {
+      Node::Input{ty, ..} | Node::Output{ty, ..} => channels(ty),
+      Node::Element(Element::PerChannel(pe, channels)) => channels.saturating_mul(ports(&Node::Element(Element::Prim(*pe)))),
+      Node::Element(Element::Prim(PrimElement::Upmix{channels, ..} | PrimElement::Downmix{channels, ..})) => *channels,
+      Node::Element(Element::Prim(PrimElement::Mix{inputs, ..} | PrimElement::Select{inputs, ..})) => *inputs,
+      Node::Element(_) => 1,
+   }
+}
+
+struct FlowSection {
+   line: usize,
+   flow_id: FlowId,
+   params: Vec<Param>,
+   nodes: Vec<(usize, NodeIx, Node)>,
+   converters: Vec<(usize, NodeIx)>,
+   defaults: Vec<(usize, NodeIx, InputNo, Value)>,
+   edges: Vec<(usize, bool, NodeIx, OutputNo, NodeIx, InputNo)>,
+}
+
+enum Line {
+   Flow(FlowId),
+   Param(Param),
+   Node(NodeIx, Node),
+   Converter(NodeIx),
+   Default(NodeIx, InputNo, Value),
+   Edge{feedback: bool, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo},
+}
+
+fn fmt_node(node: &Node) -> String {
+   match node {
+      Node::Input{no, ty, name} => format!("input {} {}{}", Into::<u32>::into(*no), fmt_type(*ty), fmt_name(name)),
+      Node::Output{no, ty, name} => format!("output {} {}{}", Into::<u32>::into(*no), fmt_type(*ty), fmt_name(name)),
+      Node::Element(Element::Flow(flow_id)) => format!("flow {}", flow_id.0),
+      Node::Element(Element::Template(flow_id, args)) =>
+         format!("template {}{}", flow_id.0, args.iter().map(|arg| format!(" {}", fmt_arg(*arg))).collect::<String>()),
+      Node::Element(Element::Prim(pe)) => format!("prim {}", fmt_prim_element(*pe)),
+      Node::Element(Element::PerChannel(pe, channels)) => format!("per-channel {} {}", channels, fmt_prim_element(*pe)),
+   }
+}
+
+fn fmt_name(name: &str) -> String {
+   match name {
+      "" => String::new(),
+      _ => format!(" \"{}\"", name.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")),
+   }
+}
+
+fn fmt_binding(binding: Binding) -> String {
+   match binding {
+      Binding::Field(node_ix, n) => format!("field {} {}", node_ix.index(), n),
+      Binding::Default(node_ix, input_no) => format!("default {} {}", node_ix.index(), Into::<u32>::into(input_no)),
+   }
+}
+
+fn fmt_prim_element(pe: PrimElement) -> String {
+   match pe {
+      PrimElement::SineOscF32{f_nyq} => format!("sine-osc-f32 {}", f_nyq),
+      PrimElement::Constant{value, f_nyq} => format!("constant {} {}", fmt_value(value), f_nyq),
+      PrimElement::Cast{from, to, f_nyq} => format!("cast {} {} {}", fmt_prim_type(from), fmt_prim_type(to), f_nyq),
+      PrimElement::Resample{ty, from_f_nyq, to_f_nyq} => format!("resample {} {} {}", fmt_prim_type(ty), from_f_nyq, to_f_nyq),
+      PrimElement::Hold{ty, f_nyq} => format!("hold {} {}", fmt_prim_type(ty), f_nyq),
+      PrimElement::Upmix{ty, f_nyq, channels} => format!("upmix {} {} {}", fmt_prim_type(ty), f_nyq, channels),
+      PrimElement::Downmix{ty, f_nyq, channels} => format!("downmix {} {} {}", fmt_prim_type(ty), f_nyq, channels),
+      PrimElement::Binary{op, ty, f_nyq} => format!("{} {} {}", fmt_binary_op(op), fmt_prim_type(ty), f_nyq),
+      PrimElement::Unary{op, ty, f_nyq} => format!("{} {} {}", fmt_unary_op(op), fmt_prim_type(ty), f_nyq),
+      PrimElement::Clamp{ty, f_nyq} => format!("clamp {} {}", fmt_prim_type(ty), f_nyq),
+      PrimElement::Mix{ty, f_nyq, inputs} => format!("mix {} {} {}", fmt_prim_type(ty), f_nyq, inputs),
+      PrimElement::Select{ty, f_nyq, inputs} => format!("select {} {} {}", fmt_prim_type(ty), f_nyq, inputs),
+   }
+}
+
+fn fmt_binary_op(op: BinaryOp) -> &'static str {
+   match op {
+      BinaryOp::Add => "add",
+      BinaryOp::Subtract => "subtract",
+      BinaryOp::Multiply => "multiply",
+      BinaryOp::Divide => "divide",
+      BinaryOp::Min => "min",
+      BinaryOp::Max => "max",
+      BinaryOp::Pow => "pow",
+   }
+}
+
+fn fmt_unary_op(op: UnaryOp) -> &'static str {
+   match op {
+      UnaryOp::Abs => "abs",
+      UnaryOp::Exp => "exp",
+      UnaryOp::Log => "log",
+      UnaryOp::DbToLinear => "db-to-linear",
+      UnaryOp::MidiToHz => "midi-to-hz",
+   }
+}
+
+fn fmt_arg(arg: Arg) -> String {
+   match arg {
+      Arg::Rate(f_nyq) => format!("rate {}", f_nyq),
+      Arg::Type(ty) => format!("type {}", fmt_prim_type(ty)),
+      Arg::Value(value) => format!("value {}", fmt_value(value)),
+   }
+}
+
+fn fmt_type(ty: Type) -> String {
+   match ty {
+      Type::Sampled{ty, f_nyq} => format!("sampled {} {}", fmt_prim_type(ty), f_nyq),
+      Type::Event(ty) => format!("event {}", fmt_prim_type(ty)),
+      Type::Bus{ty, f_nyq, channels} => format!("bus {} {} {}", fmt_prim_type(ty), f_nyq, channels),
+   }
+}
+
+fn fmt_prim_type(ty: PrimType) -> &'static str {
+   match ty {
+      PrimType::F32 => "f32",
+      PrimType::C32 => "c32",
+      PrimType::U32 => "u32",
+      PrimType::I32 => "i32",
+   }
+}
+
+fn fmt_value(value: Value) -> String {
+   match value {
+      Value::F32(x) => format!("f32 {:?}", f32::from(x)),
+      Value::C32(x) => format!("c32 {:?} {:?}", f32::from(x.re), f32::from(x.im)),
+      Value::U32(x) => format!("u32 {}", x),
+      Value::I32(x) => format!("i32 {}", x),
+   }
+}
+
+
+fn header(i: &str) -> IResult<&str, u32> {
+   preceded(pair(tag(MAGIC), space1), integer)(i)
+}
+
+fn statement(i: &str) -> IResult<&str, Line> {
+   alt((
+      map(preceded(pair(tag("flow"), space1), integer), |id| Line::Flow(FlowId(id))),
+      map(tuple((tag("param"), name, space1, arg, many0(preceded(space1, binding)))), |(_, name, _, default, bindings)|
+         Line::Param(Param{name, default, bindings})),
+      map(tuple((tag("node"), space1, node_ix, space1, node)), |(_, _, node_ix, _, node)| Line::Node(node_ix, node)),
+      map(preceded(pair(tag("converter"), space1), node_ix), Line::Converter),
+      map(tuple((tag("default"), space1, node_ix, space1, integer, space1, value)), |(_, _, node_ix, _, input_no, _, value): (_, _, _, _, u32, _, _)|
+         Line::Default(node_ix, input_no.into(), value)),
+      map(
+         tuple((alt((tag("edge"), tag("feedback"))), space1, node_ix, space1, integer, space1, node_ix, space1, integer)),
+         |(kind, _, source, _, output_no, _, target, _, input_no): (_, _, _, _, u32, _, _, _, u32)|
+            Line::Edge{feedback: kind == "feedback", source, output_no: output_no.into(), target, input_no: input_no.into()}
+      ),
+   ))(i)
+}
+
+fn node(i: &str) -> IResult<&str, Node> {
+   alt((
+      map(tuple((tag("input"), space1, integer, space1, ty, name)), |(_, _, no, _, ty, name): (_, _, u32, _, _, _)|
+         Node::Input{no: no.into(), ty, name}),
+      map(tuple((tag("output"), space1, integer, space1, ty, name)), |(_, _, no, _, ty, name): (_, _, u32, _, _, _)|
+         Node::Output{no: no.into(), ty, name}),
+      map(preceded(pair(tag("flow"), space1), integer), |id| Node::Element(Element::Flow(FlowId(id)))),
+      map(pair(preceded(pair(tag("template"), space1), integer), many0(preceded(space1, arg))), |(id, args)|
+         Node::Element(Element::Template(FlowId(id), args))),
+      map(preceded(pair(tag("prim"), space1), prim_element), |pe| Node::Element(Element::Prim(pe))),
+      map(tuple((tag("per-channel"), space1, integer, space1, prim_element)), |(_, _, channels, _, pe)|
+         Node::Element(Element::PerChannel(pe, channels))),
+   ))(i)
+}
+
+fn prim_element(i: &str) -> IResult<&str, PrimElement> {
+   alt((
+      map(preceded(pair(tag("sine-osc-f32"), space1), integer), |f_nyq| PrimElement::SineOscF32{f_nyq}),
+      map(tuple((tag("constant"), space1, value, space1, integer)), |(_, _, value, _, f_nyq)| PrimElement::Constant{value, f_nyq}),
+      map(tuple((tag("cast"), space1, prim_type, space1, prim_type, space1, integer)), |(_, _, from, _, to, _, f_nyq)|
+         PrimElement::Cast{from, to, f_nyq}),
+      map(tuple((tag("resample"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, from_f_nyq, _, to_f_nyq)|
+         PrimElement::Resample{ty, from_f_nyq, to_f_nyq}),
+      map(tuple((tag("hold"), space1, prim_type, space1, integer)), |(_, _, ty, _, f_nyq)| PrimElement::Hold{ty, f_nyq}),
+      map(tuple((tag("upmix"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, f_nyq, _, channels)|
+         PrimElement::Upmix{ty, f_nyq, channels}),
+      map(tuple((tag("downmix"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, f_nyq, _, channels)|
+         PrimElement::Downmix{ty, f_nyq, channels}),
+      map(tuple((binary_op, space1, prim_type, space1, integer)), |(op, _, ty, _, f_nyq)| PrimElement::Binary{op, ty, f_nyq}),
+      map(tuple((unary_op, space1, prim_type, space1, integer)), |(op, _, ty, _, f_nyq)| PrimElement::Unary{op, ty, f_nyq}),
+      map(tuple((tag("clamp"), space1, prim_type, space1, integer)), |(_, _, ty, _, f_nyq)| PrimElement::Clamp{ty, f_nyq}),
+      map(tuple((tag("mix"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, f_nyq, _, inputs)|
+         PrimElement::Mix{ty, f_nyq, inputs}),
+      map(tuple((tag("select"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, f_nyq, _, inputs)|
+         PrimElement::Select{ty, f_nyq, inputs}),
+   ))(i)
+}
+
+fn binary_op(i: &str) -> IResult<&str, BinaryOp> {
+   alt((
+      map(tag("add"), |_| BinaryOp::Add),
+      map(tag("subtract"), |_| BinaryOp::Subtract),
+      map(tag("multiply"), |_| BinaryOp::Multiply),
+      map(tag("divide"), |_| BinaryOp::Divide),
+      map(tag("min"), |_| BinaryOp::Min),
+      map(tag("max"), |_| BinaryOp::Max),
+      map(tag("pow"), |_| BinaryOp::Pow),
+   ))(i)
+}
+
+fn unary_op(i: &str) -> IResult<&str, UnaryOp> {
+   alt((
+      map(tag("abs"), |_| UnaryOp::Abs),
+      map(tag("exp"), |_| UnaryOp::Exp),
+      map(tag("log"), |_| UnaryOp::Log),
+      map(tag("db-to-linear"), |_| UnaryOp::DbToLinear),
+      map(tag("midi-to-hz"), |_| UnaryOp::MidiToHz),
+   ))(i)
+}
+
+fn arg(i: &str) -> IResult<&str, Arg> {
+   alt((
+      map(preceded(pair(tag("rate"), space1), integer), Arg::Rate),
+      map(preceded(pair(tag("type"), space1), prim_type), Arg::Type),
+      map(preceded(pair(tag("value"), space1), value), Arg::Value),
+   ))(i)
+}
+
+fn binding(i: &str) -> IResult<&str, Binding> {
+   alt((
+      map(tuple((tag("field"), space1, node_ix, space1, integer)), |(_, _, node_ix, _, n)| Binding::Field(node_ix, n)),
+      map(tuple((tag("default"), space1, node_ix, space1, integer)), |(_, _, node_ix, _, input_no): (_, _, _, _, u32)|
+         Binding::Default(node_ix, input_no.into())),
+   ))(i)
+}
+
+fn ty(i: &str) -> IResult<&str, Type> {
+   alt((
+      map(tuple((tag("sampled"), space1, prim_type, space1, integer)), |(_, _, ty, _, f_nyq)| Type::Sampled{ty, f_nyq}),
+      map(preceded(pair(tag("event"), space1), prim_type), Type::Event),
+      map(tuple((tag("bus"), space1, prim_type, space1, integer, space1, integer)), |(_, _, ty, _, f_nyq, _, channels)| Type::Bus{ty, f_nyq, channels}),
+   ))(i)
+}
+
+fn prim_type(i: &str) -> IResult<&str, PrimType> {
+   alt((
+      map(tag("f32"), |_| PrimType::F32),
+      map(tag("c32"), |_| PrimType::C32),
+      map(tag("u32"), |_| PrimType::U32),
+      map(tag("i32"), |_| PrimType::I32),
+   ))(i)
+}
+
+fn value(i: &str) -> IResult<&str, Value> {
+   alt((
+      map(preceded(pair(tag("f32"), space1), float), |x| Value::F32(x.into())),
+      map(tuple((tag("c32"), space1, float, space1, float)), |(_, _, re, _, im)|
+         Value::C32(num::complex::Complex::new(re.into(), im.into()))),
+      map(preceded(pair(tag("u32"), space1), integer), Value::U32),
+      map(preceded(pair(tag("i32"), space1), integer), Value::I32),
+   ))(i)
+}
+
+fn name(i: &str) -> IResult<&str, String> {
+   map(opt(preceded(space1, string)), Option::unwrap_or_default)(i)
+}
+
+fn string(i: &str) -> IResult<&str, String> {
+   let escape = alt((map(char('\\'), |_| "\\"), map(char('"'), |_| "\""), map(char('n'), |_| "\n")));
+   delimited(char('"'), map(opt(escaped_transform(is_not("\\\""), '\\', escape)), Option::unwrap_or_default), char('"'))(i)
+}
+
+fn node_ix(i: &str) -> IResult<&str, NodeIx> {
+   map(integer, |ix: u32| NodeIx::new(ix as usize))(i)
+}
+
+fn integer<T: FromStr>(i: &str) -> IResult<&str, T> {
+   map_res(recognize(pair(opt(char('-')), digit1)), str::parse)(i)
+}
+
+fn float(i: &str) -> IResult<&str, f32> {
+   map_res(take_till1(char::is_whitespace), str::parse)(i)
+}
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn round_trip(store: &Store) -> (String, Store) {
+      let mut text = Vec::new();
+      write(store, &mut text).unwrap();
+      let loaded = read(&text[..]).unwrap();
+      (String::from_utf8(text).unwrap(), loaded)
+   }
+
+   fn text(store: &Store) -> String { round_trip(store).0 }
+
+   #[test]
+   fn patches_read_back_as_written() {
+      let mut store = Store::new();
+      let template = store.add_flow();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let (_, input) = store.add_input(template, "in \"quoted\"\n", ty).unwrap();
+      let gain = store.add_element(template, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(template, "", ty).unwrap();
+      store.add_edge(template, input, OutputNo(0), gain, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(template, gain, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      store.set_default(template, gain, InputNo(1), Some(Value::F32(0.5.into()))).unwrap();
+      store.set_params(template, vec![
+         Param{name: String::new(), default: Arg::Value(Value::F32(0.5.into())), bindings: vec![Binding::Default(gain, InputNo(1))]},
+         Param{name: "rate".into(), default: Arg::Rate(1000), bindings: vec![Binding::Field(gain, 0), Binding::Field(output, 0)]},
+      ]).unwrap();
+
+      let flow = store.add_flow();
+      let args = vec![Arg::Value(Value::F32(0.25.into())), Arg::Rate(1000)];
+      let instance = store.add_element(flow, Element::Template(template, args)).unwrap();
+      let (_, output) = store.add_output(flow, "out", ty).unwrap();
+      store.add_edge(flow, instance, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_feedback_edge(flow, instance, OutputNo(0), instance, InputNo(0)).unwrap();
+
+      let (written, loaded) = round_trip(&store);
+      assert!(written.contains("param value f32 0.5 default 1 1\n"), "{}", written);
+      assert_eq!(loaded.flow(template).unwrap().params(), store.flow(template).unwrap().params());
+      assert_eq!(text(&loaded), written);
+   }
+
+   #[test]
+   fn corrupt_patches_are_refused() {
+      let read = |nodes: &str| read(format!("orgument-patch {}\n\nflow 0\n{}", VERSION, nodes).as_bytes()).err();
+      assert!(matches!(read("node 0 input 0 event f32\nnode 1 input 0 event f32\n"), Some(PatchError::DuplicateInput{line: 5, no: InputNo(0)})));
+      assert!(matches!(read("node 0 output 0 event f32\nnode 1 output 0 event f32\n"), Some(PatchError::DuplicateOutput{line: 5, no: OutputNo(0)})));
+      assert!(matches!(read("node 4000000000 prim sine-osc-f32 1000\n"), Some(PatchError::NodeLimit{line: 4, ..})));
+      assert!(matches!(read("node 0 prim select f32 1000 4000000000\n"), Some(PatchError::PortLimit{line: 4, ..})));
+      assert!(matches!(read("node 0 per-channel 1000 mix f32 1000 1000\n"), Some(PatchError::PortLimit{line: 4, ..})));
+      assert!(matches!(read("node 0 output 0 bus f32 1000 4000000000\n"), Some(PatchError::PortLimit{line: 4, ..})));
+      for version in [0, VERSION + 1] {
+         assert!(matches!(super::read(format!("orgument-patch {}\n", version).as_bytes()), Err(PatchError::UnsupportedVersion(v)) if v == version));
+      }
+   }
+
+   // The frequency the player sets is the default of the oscillator's unconnected input.
+   #[test]
+   fn shipped_patch_plays_the_oscillator_default() {
+      let store = read(&include_bytes!("../../patches/sine.orgp")[..]).unwrap();
+      let flow = store.flow(store.flow_store().ids().next().unwrap()).unwrap();
+      let (osc, _) = flow.nodes().find(|(_, node)| matches!(node, Node::Element(Element::Prim(PrimElement::SineOscF32{..})))).unwrap();
+      assert_eq!(flow.default(osc, InputNo(0)), Some(Value::F32(440.0.into())));
+      assert!(flow.edges().all(|(_, _, target, edge)| (target, edge.input_no()) != (osc, InputNo(0))));
+      assert!(flow.edges().any(|(_, source, _, _)| source == osc));
+   }
+}
@@ -0,0 +1,315 @@
+use super::{
+   PrimElementProcessor,
+   OutputNo, InputNo, Value, PrimType, super::flow_store::FlowStore,
+   super::processor::{Buffer, GenericSampledBuffer, GenericEventBuffer, Inputs, Outputs}, super::simd,
+};
+use num::complex::Complex;
+
+// Conversions go through `Complex<f64>`, which holds every `PrimType` without loss.
+trait Sample: Copy {
+   fn to_complex(self) -> Complex<f64>;
+   fn from_complex(x: Complex<f64>) -> Self;
+}
+impl Sample for f32 {
+   fn to_complex(self) -> Complex<f64> { Complex::new(self as f64, 0.0) }
+   fn from_complex(x: Complex<f64>) -> Self { x.re as f32 }
+}
+impl Sample for Complex<f32> {
+   fn to_complex(self) -> Complex<f64> { Complex::new(self.re as f64, self.im as f64) }
+   fn from_complex(x: Complex<f64>) -> Self { Complex::new(x.re as f32, x.im as f32) }
+}
+impl Sample for u32 {
+   fn to_complex(self) -> Complex<f64> { Complex::new(self as f64, 0.0) }
+   fn from_complex(x: Complex<f64>) -> Self { x.re.round() as u32 }
+}
+impl Sample for i32 {
+   fn to_complex(self) -> Complex<f64> { Complex::new(self as f64, 0.0) }
+   fn from_complex(x: Complex<f64>) -> Self { x.re.round() as i32 }
+}
+
+pub(super) fn cast_value(x: Value, to: PrimType) -> Value {
+   value_from_complex(value_to_complex(x), to)
+}
+
+// The mean `downmix` makes of `channels` channels that all hold `x`.
+pub(super) fn downmix_value(x: Value, channels: u32) -> Value {
+   let (ty, x) = (x.Type(), value_to_complex(x));
+   (0..channels).fold(cast_value(Value::U32(0), ty), |y, channel| {
+      let (y, weight) = (value_to_complex(y), 1.0 / (channel + 1) as f64);
+      value_from_complex(y + (x - y) * weight, ty)
+   })
+}
+
+fn value_to_complex(x: Value) -> Complex<f64> {
+   match x {
+      Value::F32(x) => f32::from(x).to_complex(),
+      Value::C32(x) => Complex::new(f32::from(x.re), f32::from(x.im)).to_complex(),
+      Value::U32(x) => x.to_complex(),
+      Value::I32(x) => x.to_complex(),
+   }
+}
+
+fn value_from_complex(x: Complex<f64>, to: PrimType) -> Value {
+   match to {
+      PrimType::F32 => Value::F32(f32::from_complex(x).into()),
+      PrimType::C32 => {
+         let x = Complex::<f32>::from_complex(x);
+         Value::C32(Complex::new(x.re.into(), x.im.into()))
+      }
+      PrimType::U32 => Value::U32(u32::from_complex(x)),
+      PrimType::I32 => Value::I32(i32::from_complex(x)),
+   }
+}
+
+macro_rules! impl_for_each_sample {
+   ($($prim_type:ident),*) => {
+      fn cast(x: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match y {
+            $( GenericSampledBuffer::$prim_type(y) => cast_into(x, &mut y.samples), )*
+         }
+      }
+
+      fn cast_into<T: Sample>(x: &GenericSampledBuffer, y: &mut [T]) {
+         match x {
+            $( GenericSampledBuffer::$prim_type(x) =>
+               y.iter_mut().zip(&x.samples).for_each(|(y,x)| *y = T::from_complex(x.to_complex())), )*
+         }
+      }
+
+      fn resample(last: &mut Complex<f64>, x: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match (x, y) {
+            $( (GenericSampledBuffer::$prim_type(x), GenericSampledBuffer::$prim_type(y)) => resample_into(last, &x.samples, &mut y.samples), )*
+            _ => unreachable!(),
+         }
+      }
+
+      fn hold(current: &mut Complex<f64>, x: Option<&GenericEventBuffer>, input: &Inputs, y: &mut GenericSampledBuffer) {
+         match (x, y) {
+            $( (Some(GenericEventBuffer::$prim_type(x)), GenericSampledBuffer::$prim_type(y)) =>
+               x.spans(y.samples.len(), |time| input.position(time)).for_each(|(span, events)| {
+                  if let Some(event) = events.last() {
+                     *current = event.value.to_complex();
+                  }
+                  y.samples[span].iter_mut().for_each(|y| *y = Sample::from_complex(*current));
+               }), )*
+            (None, y) => {
+               let current = *current;
+               match y {
+                  $( GenericSampledBuffer::$prim_type(y) => y.samples.iter_mut().for_each(|y| *y = Sample::from_complex(current)), )*
+               }
+            }
+            _ => unreachable!(),
+         }
+      }
+
+      // Averages the channels one at a time, moving the mean of those before towards each, so
+      // that a downmix of identical channels is lossless.
+      fn downmix(xs: &[Buffer], y: &mut GenericSampledBuffer) {
+         match y {
+            $( GenericSampledBuffer::$prim_type(y) => {
+               simd::fill(&mut y.samples, Default::default());
+               xs.iter().enumerate().for_each(|(channel, x)| {
+                  let x = unwrap_match!(x, Buffer::Sampled(GenericSampledBuffer::$prim_type(x)) => &x.samples);
+                  let weight = 1.0 / (channel + 1) as f64;
+                  simd::update(&mut y.samples, x, |y, x| Sample::from_complex(y.to_complex() + (x.to_complex() - y.to_complex()) * weight));
+               });
+            } )*
+         }
+      }
+   }
+}
+enumerate_prim_types!{impl_for_each_sample}
+
+// Both blocks cover the same span of time. Downsampling averages the input samples that fall
+// into each output sample, upsampling interpolates linearly from the previous block's last
+// sample, so that block boundaries stay continuous.
+fn resample_into<T: Sample>(last: &mut Complex<f64>, x: &[T], y: &mut [T]) {
+   if x.is_empty() {
+      y.iter_mut().for_each(|y| *y = T::from_complex(*last));
+      return
+   }
+
+   let step = x.len() as f64 / y.len() as f64;
+   if step > 1.0 {
+      y.iter_mut().enumerate().for_each(|(i, y)| {
+         let (begin, end) = ((i as f64 * step) as usize, (((i + 1) as f64 * step) as usize).min(x.len()));
+         let sum = x[begin..end].iter().fold(Complex::new(0.0, 0.0), |sum, x| sum + x.to_complex());
+         *y = T::from_complex(sum / (end - begin) as f64);
+      });
+   }
+   else {
+      y.iter_mut().enumerate().for_each(|(i, y)| {
+         let pos = (i + 1) as f64 * step - 1.0;
+         let (ix, frac) = (pos.floor(), pos - pos.floor());
+         let x0 = if ix < 0.0 { *last } else { x[ix as usize].to_complex() };
+         let x1 = x.get((ix + 1.0) as usize).map_or(x0, |x1| x1.to_complex());
+         *y = T::from_complex(x0 + (x1 - x0) * frac);
+      });
+   }
+   *last = x[x.len() - 1].to_complex();
+}
+
+pub struct Cast;
+impl PrimElementProcessor for Cast {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+
+         if let Some(x) = input.get(InputNo(0)) {
+            cast(unwrap_match!(&*x, Buffer::Sampled(x) => x), y);
+         }
+         else { y.clear(); }
+      }
+   }
+}
+
+pub struct Resample {
+   last: Complex<f64>,
+}
+impl Resample {
+   pub(super) fn new() -> Self {
+      Self{last: Complex::new(0.0, 0.0)}
+   }
+}
+impl PrimElementProcessor for Resample {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+
+         if let Some(x) = input.get(InputNo(0)) {
+            resample(&mut self.last, unwrap_match!(&*x, Buffer::Sampled(x) => x), y);
+         }
+         else { y.clear(); }
+      }
+   }
+}
+
+pub struct Hold {
+   current: Complex<f64>,
+}
+impl Hold {
+   pub(super) fn new() -> Self {
+      Self{current: Complex::new(0.0, 0.0)}
+   }
+}
+impl PrimElementProcessor for Hold {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+
+         let x = input.get(InputNo(0));
+         hold(&mut self.current, x.as_deref().map(|x| unwrap_match!(x, Buffer::Event(x) => x)), input, y);
+      }
+   }
+}
+
+pub struct Upmix;
+impl PrimElementProcessor for Upmix {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Bus(y) => y);
+         let x = input.get(InputNo(0));
+         y.iter_mut().for_each(|y| match &x {
+            Some(x) => y.copy_from(x),
+            None => {
+               y.update_size(buffer_sz);
+               y.clear();
+            }
+         });
+      }
+   }
+}
+
+pub struct Downmix;
+impl PrimElementProcessor for Downmix {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+
+         if let Some(x) = input.get(InputNo(0)) {
+            downmix(unwrap_match!(&*x, Buffer::Bus(xs) => xs), y);
+         }
+         else { y.clear(); }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::super::{super::{Store, EdgeMode, Type, element::Element, processor::GenericEventBuffer}, PrimElement};
+   use super::*;
+   use linear_map::LinearMap;
+
+   #[test]
+   fn hold_keeps_late_events_on_slow_streams() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      store.add_input(flow, "clock", Type::Sampled{ty: PrimType::F32, f_nyq: 22050}).unwrap();
+      let (_, events) = store.add_input(flow, "events", Type::Event(PrimType::F32)).unwrap();
+      let (output_no, output) = store.add_output(flow, "held", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      let hold = store.add_element(flow, Element::Prim(PrimElement::Hold{ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      store.add_edge(flow, events, OutputNo(0), hold, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, hold, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let mut held = Vec::new();
+      for block in 0..3 {
+         let mut clock = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 22050});
+         clock.update_size(64);
+         let mut event = Buffer::new(Type::Event(PrimType::F32));
+         if block == 0 {
+            unwrap_match!(&mut event, Buffer::Event(GenericEventBuffer::F32(event)) => event.push(63, 0.5));
+         }
+         let mut y = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+         let input: LinearMap<_, &mut Buffer> = vec![(InputNo(0), &mut clock), (InputNo(1), &mut event)].into_iter().collect();
+         let mut output: LinearMap<_, &mut Buffer> = vec![(output_no, &mut y)].into_iter().collect();
+         store.compute_outplace(flow, &mut output, &input, 64);
+         drop(output);
+         held.extend(unwrap_match!(y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y.samples));
+      }
+      assert_eq!(held.len(), 8);
+      assert_eq!(held[0], 0.0);
+      assert!(held[1..].iter().all(|&x| x == 0.5), "{:?}", held);
+   }
+
+   #[test]
+   fn downmix_averages_channels() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let ty = Type::Bus{ty: PrimType::F32, f_nyq: 1000, channels: 3};
+      let (_, bus) = store.add_input(flow, "bus", ty).unwrap();
+      let (output_no, output) = store.add_output(flow, "mean", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      let downmix = store.add_element(flow, Element::Prim(PrimElement::Downmix{ty: PrimType::F32, f_nyq: 1000, channels: 3})).unwrap();
+      store.add_edge(flow, bus, OutputNo(0), downmix, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, downmix, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let mut x = Buffer::new(ty);
+      unwrap_match!(&mut x, Buffer::Bus(channels) => channels.iter_mut().zip(&[1.0, 2.0, 6.0]).for_each(|(channel, &x)| {
+         unwrap_match!(channel, Buffer::Sampled(GenericSampledBuffer::F32(channel)) => channel.samples = vec![0.1, x]);
+      }));
+      let mut y = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+      let input: LinearMap<_, &mut Buffer> = vec![(InputNo(0), &mut x)].into_iter().collect();
+      let mut output: LinearMap<_, &mut Buffer> = vec![(output_no, &mut y)].into_iter().collect();
+      store.compute_outplace(flow, &mut output, &input, 2);
+      drop(output);
+      assert_eq!(unwrap_match!(y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y.samples), vec![0.1, 3.0]);
+   }
+}
@@ -1,21 +1,65 @@
 mod sine_osc;
 mod constant;
 mod pure;
+mod convert;
 
-use super::{InputNo, OutputNo, Type, Value, PrimType, flow_store::FlowStore, processor::Buffer};
+use super::{InputNo, OutputNo, Type, Value, PrimType, Port, Unit, flow_store::FlowStore, processor::{Inputs, Outputs}};
 use linear_map::LinearMap;
-use std::cell::{RefCell, RefMut};
+use atomic_refcell::AtomicRefCell;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum BinaryOp {
+   Add,
+   Subtract,
+   Multiply,
+   Divide,
+   Min,
+   Max,
+   Pow,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum UnaryOp {
+   Abs,
+   Exp,
+   Log,
+   DbToLinear,
+   MidiToHz,
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum PrimElement {
    SineOscF32{f_nyq: u64},
    Constant{value: Value, f_nyq: u64},
+   Cast{from: PrimType, to: PrimType, f_nyq: u64},
+   Resample{ty: PrimType, from_f_nyq: u64, to_f_nyq: u64},
+   Hold{ty: PrimType, f_nyq: u64},
+   Upmix{ty: PrimType, f_nyq: u64, channels: u32},
+   Downmix{ty: PrimType, f_nyq: u64, channels: u32},
+   Binary{op: BinaryOp, ty: PrimType, f_nyq: u64},
+   Unary{op: UnaryOp, ty: PrimType, f_nyq: u64},
+   Clamp{ty: PrimType, f_nyq: u64},
+   Mix{ty: PrimType, f_nyq: u64, inputs: u32},
+   Select{ty: PrimType, f_nyq: u64, inputs: u32},
 }
 impl PrimElement {
    pub fn input_types(&self) -> LinearMap<InputNo, Type> {
       match *self {
          PrimElement::SineOscF32{f_nyq} => linear_map!{InputNo(0) => Type::Sampled{ty: PrimType::F32, f_nyq}},
          PrimElement::Constant{..} => LinearMap::new(),
+         PrimElement::Cast{from, f_nyq, ..} => linear_map!{InputNo(0) => Type::Sampled{ty: from, f_nyq}},
+         PrimElement::Resample{ty, from_f_nyq, ..} => linear_map!{InputNo(0) => Type::Sampled{ty, f_nyq: from_f_nyq}},
+         PrimElement::Hold{ty, ..} => linear_map!{InputNo(0) => Type::Event(ty)},
+         PrimElement::Upmix{ty, f_nyq, ..} => linear_map!{InputNo(0) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Downmix{ty, f_nyq, channels} => linear_map!{InputNo(0) => Type::Bus{ty, f_nyq, channels}},
+         PrimElement::Binary{ty, f_nyq, ..} => linear_map!{InputNo(0) => Type::Sampled{ty, f_nyq}, InputNo(1) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Unary{ty, f_nyq, ..} => linear_map!{InputNo(0) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Clamp{ty, f_nyq} =>
+            linear_map!{InputNo(0) => Type::Sampled{ty, f_nyq}, InputNo(1) => Type::Sampled{ty, f_nyq}, InputNo(2) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Mix{ty, f_nyq, inputs} => (0..inputs).map(|no| (InputNo(no), Type::Sampled{ty, f_nyq})).collect(),
+         PrimElement::Select{ty, f_nyq, inputs} => std::iter::once((InputNo(0), Type::Sampled{ty: PrimType::U32, f_nyq}))
+            .chain((1..=inputs).map(|no| (InputNo(no), Type::Sampled{ty, f_nyq})))
+            .collect(),
       }
    }
 
@@ -23,20 +67,259 @@ impl PrimElement {
       match *self {
          PrimElement::SineOscF32{f_nyq} => linear_map!{OutputNo(0) => Type::Sampled{ty: PrimType::F32, f_nyq}},
          PrimElement::Constant{value, f_nyq} => linear_map!{OutputNo(0) => Type::Sampled{ty: value.Type(), f_nyq}},
+         PrimElement::Cast{to, f_nyq, ..} => linear_map!{OutputNo(0) => Type::Sampled{ty: to, f_nyq}},
+         PrimElement::Resample{ty, to_f_nyq, ..} => linear_map!{OutputNo(0) => Type::Sampled{ty, f_nyq: to_f_nyq}},
+         PrimElement::Hold{ty, f_nyq} => linear_map!{OutputNo(0) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Upmix{ty, f_nyq, channels} => linear_map!{OutputNo(0) => Type::Bus{ty, f_nyq, channels}},
+         PrimElement::Downmix{ty, f_nyq, ..} => linear_map!{OutputNo(0) => Type::Sampled{ty, f_nyq}},
+         PrimElement::Binary{ty, f_nyq, ..} | PrimElement::Unary{ty, f_nyq, ..} | PrimElement::Clamp{ty, f_nyq}
+            | PrimElement::Mix{ty, f_nyq, ..} | PrimElement::Select{ty, f_nyq, ..} =>
+            linear_map!{OutputNo(0) => Type::Sampled{ty, f_nyq}},
+      }
+   }
+
+   pub fn input_ports(&self) -> LinearMap<InputNo, Port> {
+      match *self {
+         PrimElement::SineOscF32{f_nyq} => linear_map!{InputNo(0) => Port{
+            name: "freq".into(), description: "Oscillation frequency".into(), unit: Unit::Hz,
+            range: Some((0.0, f_nyq as f64)), default: Some(Value::F32((f_nyq as f32).min(440.0).into())),
+         }},
+         PrimElement::Constant{..} => LinearMap::new(),
+         PrimElement::Cast{..} | PrimElement::Resample{..} | PrimElement::Hold{..} | PrimElement::Upmix{..} | PrimElement::Downmix{..} =>
+            linear_map!{InputNo(0) => Port{description: "Stream to convert".into(), ..Port::named("in")}},
+         PrimElement::Binary{op, ty, ..} => {
+            let (zero, one) = (Some(pure::zero(ty)), Some(pure::one(ty)));
+            let ((a, a_default), (b, b_default)) = match op {
+               BinaryOp::Add => (("First summand", zero), ("Second summand", zero)),
+               BinaryOp::Subtract => (("Minuend", zero), ("Subtrahend", zero)),
+               BinaryOp::Multiply => (("First factor", one), ("Second factor", one)),
+               BinaryOp::Divide => (("Dividend", zero), ("Divisor", one)),
+               BinaryOp::Min | BinaryOp::Max => (("First operand", None), ("Second operand", None)),
+               BinaryOp::Pow => (("Base", None), ("Exponent", one)),
+            };
+            linear_map!{
+               InputNo(0) => Port{description: a.into(), default: a_default, ..Port::named("a")},
+               InputNo(1) => Port{description: b.into(), default: b_default, ..Port::named("b")},
+            }
+         }
+         PrimElement::Unary{op: UnaryOp::DbToLinear, ty, ..} => linear_map!{InputNo(0) => Port{
+            description: "Level in decibels".into(), unit: Unit::Db, default: Some(pure::zero(ty)), ..Port::named("in")
+         }},
+         PrimElement::Unary{op: UnaryOp::MidiToHz, ty, ..} => linear_map!{InputNo(0) => Port{
+            description: "MIDI note number".into(), range: Some((0.0, 127.0)), default: Some(cast_value(Value::U32(69), ty)), ..Port::named("note")
+         }},
+         PrimElement::Unary{..} => linear_map!{InputNo(0) => Port{description: "Operand".into(), ..Port::named("in")}},
+         PrimElement::Clamp{ty, ..} => linear_map!{
+            InputNo(0) => Port{description: "Stream to clamp".into(), ..Port::named("in")},
+            InputNo(1) => Port{description: "Lower bound".into(), default: Some(pure::zero(ty)), ..Port::named("min")},
+            InputNo(2) => Port{description: "Upper bound".into(), default: Some(pure::one(ty)), ..Port::named("max")},
+         },
+         PrimElement::Mix{inputs, ..} => (0..inputs).map(|no| (InputNo(no), Port{
+            description: "Stream to mix".into(), ..Port::named(format!("in{}", no))
+         })).collect(),
+         PrimElement::Select{inputs, ..} => std::iter::once((InputNo(0), Port{
+            description: "Number of the stream to pass on".into(), range: Some((0.0, inputs.saturating_sub(1) as f64)),
+            default: Some(Value::U32(0)), ..Port::named("index")
+         })).chain((1..=inputs).map(|no| (InputNo(no), Port{
+            description: "Stream to choose from".into(), ..Port::named(format!("in{}", no - 1))
+         }))).collect(),
+      }
+   }
+
+   pub fn output_ports(&self) -> LinearMap<OutputNo, Port> {
+      match *self {
+         PrimElement::SineOscF32{..} => linear_map!{OutputNo(0) => Port{
+            description: "Sine wave".into(), range: Some((-1.0, 1.0)), ..Port::named("out")
+         }},
+         PrimElement::Constant{..} => linear_map!{OutputNo(0) => Port{
+            description: "The constant value".into(), ..Port::named("out")
+         }},
+         PrimElement::Cast{..} | PrimElement::Resample{..} | PrimElement::Hold{..} | PrimElement::Upmix{..} | PrimElement::Downmix{..} =>
+            linear_map!{OutputNo(0) => Port{description: "Converted stream".into(), ..Port::named("out")}},
+         PrimElement::Binary{op, ..} => {
+            let description = match op {
+               BinaryOp::Add => "Sum",
+               BinaryOp::Subtract => "Difference",
+               BinaryOp::Multiply => "Product",
+               BinaryOp::Divide => "Quotient",
+               BinaryOp::Min => "Smaller operand",
+               BinaryOp::Max => "Larger operand",
+               BinaryOp::Pow => "Power",
+            };
+            linear_map!{OutputNo(0) => Port{description: description.into(), ..Port::named("out")}}
+         }
+         PrimElement::Unary{op, ..} => {
+            let (description, unit) = match op {
+               UnaryOp::Abs => ("Absolute value", Unit::None),
+               UnaryOp::Exp => ("Exponential", Unit::None),
+               UnaryOp::Log => ("Natural logarithm", Unit::None),
+               UnaryOp::DbToLinear => ("Linear gain", Unit::None),
+               UnaryOp::MidiToHz => ("Note frequency", Unit::Hz),
+            };
+            linear_map!{OutputNo(0) => Port{description: description.into(), unit, ..Port::named("out")}}
+         }
+         PrimElement::Clamp{..} => linear_map!{OutputNo(0) => Port{description: "Clamped stream".into(), ..Port::named("out")}},
+         PrimElement::Mix{..} => linear_map!{OutputNo(0) => Port{description: "Sum of all streams".into(), ..Port::named("out")}},
+         PrimElement::Select{..} => linear_map!{OutputNo(0) => Port{description: "The chosen stream".into(), ..Port::named("out")}},
+      }
+   }
+
+   // The outputs of a stateless element fed with constant inputs, or `None` if they can't be
+   // computed ahead of time. The value of a bus is that of each of its channels.
+   pub(super) fn fold(&self, inputs: &LinearMap<InputNo, Value>) -> Option<LinearMap<OutputNo, Value>> {
+      match *self {
+         PrimElement::Constant{value, ..} => Some(linear_map!{OutputNo(0) => value}),
+         PrimElement::Cast{to, ..} => Some(linear_map!{OutputNo(0) => convert::cast_value(*inputs.get(&InputNo(0))?, to)}),
+         PrimElement::Upmix{..} => Some(linear_map!{OutputNo(0) => *inputs.get(&InputNo(0))?}),
+         PrimElement::Downmix{channels, ..} => Some(linear_map!{OutputNo(0) => convert::downmix_value(*inputs.get(&InputNo(0))?, channels)}),
+         PrimElement::Binary{op, ..} =>
+            Some(linear_map!{OutputNo(0) => pure::binary_value(op, *inputs.get(&InputNo(0))?, *inputs.get(&InputNo(1))?)}),
+         PrimElement::Unary{op, ..} => Some(linear_map!{OutputNo(0) => pure::unary_value(op, *inputs.get(&InputNo(0))?)}),
+         PrimElement::Clamp{..} => Some(linear_map!{
+            OutputNo(0) => pure::clamp_value(*inputs.get(&InputNo(0))?, *inputs.get(&InputNo(1))?, *inputs.get(&InputNo(2))?)
+         }),
+         PrimElement::Mix{ty, ..} =>
+            Some(linear_map!{OutputNo(0) => inputs.values().fold(pure::zero(ty), |sum, x| pure::binary_value(BinaryOp::Add, sum, *x))}),
+         PrimElement::Select{ty, ..} => {
+            let index = unwrap_match!(*inputs.get(&InputNo(0))?, Value::U32(index) => index);
+            Some(linear_map!{OutputNo(0) => index.checked_add(1).and_then(|no| inputs.get(&InputNo(no))).copied().unwrap_or(pure::zero(ty))})
+         }
+         // Resampling starts out from silence.
+         PrimElement::SineOscF32{..} | PrimElement::Hold{..} | PrimElement::Resample{..} => None,
       }
    }
+
+   // This element retyped to accept a stream of type `ty` on `input_no`, if that input is
+   // generic over its type. The other generic ports follow.
+   pub fn infer(&self, input_no: InputNo, ty: Type) -> Option<PrimElement> {
+      let (ty, f_nyq) = option_match!(ty, Type::Sampled{ty, f_nyq} => (ty, f_nyq))?;
+      let pe = match *self {
+         PrimElement::Binary{op, ..} => PrimElement::Binary{op, ty, f_nyq},
+         PrimElement::Unary{op, ..} => PrimElement::Unary{op, ty, f_nyq},
+         PrimElement::Clamp{..} => PrimElement::Clamp{ty, f_nyq},
+         PrimElement::Mix{inputs, ..} => PrimElement::Mix{ty, f_nyq, inputs},
+         PrimElement::Select{inputs, ..} if input_no != InputNo(0) => PrimElement::Select{ty, f_nyq, inputs},
+         _ => return None,
+      };
+      Some(pe).filter(|pe| pe.input_types().contains_key(&input_no))
+   }
+
+   // The chain of converters that turns a stream of type `from` into one of type `to`,
+   // or `None` if there is no such conversion.
+   pub fn converters(from: Type, to: Type) -> Option<Vec<PrimElement>> {
+      let mut converters = Vec::new();
+      let (from_ty, from_f_nyq) = match (from, to) {
+         (Type::Sampled{ty, f_nyq}, Type::Sampled{..}) => (ty, f_nyq),
+         (Type::Event(ty), Type::Sampled{f_nyq, ..}) => {
+            converters.push(PrimElement::Hold{ty, f_nyq});
+            (ty, f_nyq)
+         }
+         _ => return None,
+      };
+      let (to_ty, to_f_nyq) = unwrap_match!(to, Type::Sampled{ty, f_nyq} => (ty, f_nyq));
+      if from_ty != to_ty {
+         converters.push(PrimElement::Cast{from: from_ty, to: to_ty, f_nyq: from_f_nyq});
+      }
+      if from_f_nyq != to_f_nyq {
+         converters.push(PrimElement::Resample{ty: to_ty, from_f_nyq, to_f_nyq});
+      }
+      Some(converters)
+   }
 }
 
-pub(super) fn mk_prim_element_processor(prim_element_id: PrimElement) -> Box<RefCell<dyn PrimElementProcessor + Send>> {
+pub(super) fn cast_value(x: Value, to: PrimType) -> Value { convert::cast_value(x, to) }
+
+// Tasks of a stage run disjoint elements, so the cells are never borrowed twice at once.
+pub(super) type BoxedPrimElementProcessor = Box<AtomicRefCell<dyn PrimElementProcessor + Send + Sync>>;
+
+pub(super) fn mk_prim_element_processor(prim_element_id: PrimElement) -> BoxedPrimElementProcessor {
    match prim_element_id {
-      PrimElement::SineOscF32{f_nyq} => Box::new(RefCell::new(sine_osc::SineOscF32::new(f_nyq))),
-      PrimElement::Constant{value, ..} => Box::new(RefCell::new(constant::Constant::new(value))),
+      PrimElement::SineOscF32{f_nyq} => Box::new(AtomicRefCell::new(sine_osc::SineOscF32::new(f_nyq))),
+      PrimElement::Constant{value, ..} => Box::new(AtomicRefCell::new(constant::Constant::new(value))),
+      PrimElement::Cast{..} => Box::new(AtomicRefCell::new(convert::Cast)),
+      PrimElement::Resample{..} => Box::new(AtomicRefCell::new(convert::Resample::new())),
+      PrimElement::Hold{..} => Box::new(AtomicRefCell::new(convert::Hold::new())),
+      PrimElement::Upmix{..} => Box::new(AtomicRefCell::new(convert::Upmix)),
+      PrimElement::Downmix{..} => Box::new(AtomicRefCell::new(convert::Downmix)),
+      PrimElement::Binary{op, ..} => Box::new(AtomicRefCell::new(pure::Binary::new(op))),
+      PrimElement::Unary{op, ..} => Box::new(AtomicRefCell::new(pure::Unary::new(op))),
+      PrimElement::Clamp{..} => Box::new(AtomicRefCell::new(pure::Clamp)),
+      PrimElement::Mix{inputs, ..} => Box::new(AtomicRefCell::new(pure::Mix::new(inputs))),
+      PrimElement::Select{inputs, ..} => Box::new(AtomicRefCell::new(pure::Select::new(inputs))),
    }
 }
 
 pub(super) trait PrimElementProcessor {
    fn compute_outplace(
-      &mut self, output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>,
+      &mut self, output: &mut Outputs, input: &Inputs,
       buffer_sz: usize, flow_store: &FlowStore,
    );
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use super::super::{Store, element::Element};
+
+   // Every port of every element has a type, defaults fit their input and lie in its range.
+   #[test]
+   fn ports_describe_every_input_and_output() {
+      let binary_ops = [BinaryOp::Add, BinaryOp::Subtract, BinaryOp::Multiply, BinaryOp::Divide, BinaryOp::Min, BinaryOp::Max, BinaryOp::Pow];
+      let unary_ops = [UnaryOp::Abs, UnaryOp::Exp, UnaryOp::Log, UnaryOp::DbToLinear, UnaryOp::MidiToHz];
+      let elements = [PrimType::F32, PrimType::C32, PrimType::U32, PrimType::I32].iter().flat_map(|&ty| {
+         let f_nyq = 1000;
+         vec![
+            PrimElement::Constant{value: cast_value(Value::U32(3), ty), f_nyq},
+            PrimElement::Cast{from: ty, to: PrimType::F32, f_nyq},
+            PrimElement::Resample{ty, from_f_nyq: f_nyq, to_f_nyq: 2000},
+            PrimElement::Hold{ty, f_nyq},
+            PrimElement::Upmix{ty, f_nyq, channels: 2},
+            PrimElement::Downmix{ty, f_nyq, channels: 2},
+            PrimElement::Clamp{ty, f_nyq},
+            PrimElement::Mix{ty, f_nyq, inputs: 3},
+            PrimElement::Select{ty, f_nyq, inputs: 3},
+         ].into_iter()
+            .chain(binary_ops.iter().map(move |&op| PrimElement::Binary{op, ty, f_nyq}))
+            .chain(unary_ops.iter().map(move |&op| PrimElement::Unary{op, ty, f_nyq}))
+      }).chain(vec![PrimElement::SineOscF32{f_nyq: 100}, PrimElement::SineOscF32{f_nyq: 22050}]);
+
+      for element in elements {
+         let (types, ports) = (element.input_types(), element.input_ports());
+         assert_eq!(types.keys().collect::<Vec<_>>(), ports.keys().collect::<Vec<_>>(), "{:?}", element);
+         assert_eq!(element.output_types().keys().collect::<Vec<_>>(), element.output_ports().keys().collect::<Vec<_>>(), "{:?}", element);
+         for (input_no, port) in ports.iter() {
+            assert!(!port.name.is_empty(), "{:?} {:?}", element, input_no);
+            if let Some(default) = port.default {
+               let ty = unwrap_match!(types[input_no], Type::Sampled{ty, ..} | Type::Bus{ty, ..} | Type::Event(ty) => ty);
+               assert_eq!(default.Type(), ty, "{:?} {:?}", element, input_no);
+               let x = match default {
+                  Value::F32(x) => Some(x.0 as f64),
+                  Value::U32(x) => Some(x as f64),
+                  Value::I32(x) => Some(x as f64),
+                  Value::C32(_) => None,
+               };
+               if let (Some(x), Some((min, max))) = (x, port.range) {
+                  assert!(min <= x && x <= max, "{:?} {:?}", element, input_no);
+               }
+            }
+         }
+      }
+   }
+
+   // The ports of a flow are named after its input and output nodes.
+   #[test]
+   fn flow_ports_take_the_names_of_their_nodes() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      store.add_input(flow, "freq", ty).unwrap();
+      store.add_output(flow, "left", ty).unwrap();
+      store.add_output(flow, "right", ty).unwrap();
+      let outer = store.add_flow();
+      let node = store.add_element(outer, Element::Flow(flow)).unwrap();
+      let node = store.flow(outer).unwrap().node(node);
+      let names = |ports: Vec<Port>| ports.into_iter().map(|port| port.name.into_owned()).collect::<Vec<_>>();
+      assert_eq!(names(node.input_ports(store.flow_store()).into_iter().map(|(_, port)| port).collect()), vec!["freq"]);
+      assert_eq!(names(node.output_ports(store.flow_store()).into_iter().map(|(_, port)| port).collect()), vec!["left", "right"]);
+   }
+}
@@ -1,56 +1,526 @@
 use super::PrimElementProcessor;
 
 use super::{
-   InputNo, OutputNo, super::flow_store::FlowStore,
-   super::processor::{Buffer, GenericSampledBuffer},
+   InputNo, OutputNo, Value, PrimType, BinaryOp, UnaryOp, super::flow_store::FlowStore,
+   super::processor::{Buffer, GenericSampledBuffer, Inputs, Outputs}, super::simd,
 };
-use linear_map::LinearMap;
-use std::cell::RefMut;
+use num::complex::Complex;
+use std::f32::consts::{LN_10, LN_2};
 
-pub struct MultiplyF32;
-impl MultiplyF32 {
-   pub(super) fn new() -> Self { Self }
+// Integers wrap around instead of overflowing, and yield zero when divided by zero. Functions
+// without an integer counterpart are computed in `f64` and rounded. Complex numbers are
+// ordered component-wise, and their absolute value is their magnitude.
+trait Arithmetic: Copy + Default {
+   fn binary(self, op: BinaryOp, other: Self) -> Self;
+   fn unary(self, op: UnaryOp) -> Self;
+   fn from_value(x: Value) -> Self;
+   fn into_value(self) -> Value;
 }
-impl PrimElementProcessor for MultiplyF32 {
+
+fn unary_f64(op: UnaryOp, x: f64) -> f64 {
+   match op {
+      UnaryOp::Abs => x.abs(),
+      UnaryOp::Exp => x.exp(),
+      UnaryOp::Log => x.ln(),
+      UnaryOp::DbToLinear => 10f64.powf(x / 20.0),
+      UnaryOp::MidiToHz => 440.0 * 2f64.powf((x - 69.0) / 12.0),
+   }
+}
+
+impl Arithmetic for f32 {
+   fn binary(self, op: BinaryOp, other: Self) -> Self {
+      match op {
+         BinaryOp::Add => self + other,
+         BinaryOp::Subtract => self - other,
+         BinaryOp::Multiply => self * other,
+         BinaryOp::Divide => self / other,
+         BinaryOp::Min => self.min(other),
+         BinaryOp::Max => self.max(other),
+         BinaryOp::Pow => self.powf(other),
+      }
+   }
+
+   fn unary(self, op: UnaryOp) -> Self { unary_f64(op, self as f64) as f32 }
+
+   fn from_value(x: Value) -> Self { unwrap_match!(x, Value::F32(x) => x.into()) }
+
+   fn into_value(self) -> Value { Value::F32(self.into()) }
+}
+
+impl Arithmetic for Complex<f32> {
+   fn binary(self, op: BinaryOp, other: Self) -> Self {
+      match op {
+         BinaryOp::Add => self + other,
+         BinaryOp::Subtract => self - other,
+         BinaryOp::Multiply => self * other,
+         BinaryOp::Divide => self / other,
+         BinaryOp::Min => Complex::new(self.re.min(other.re), self.im.min(other.im)),
+         BinaryOp::Max => Complex::new(self.re.max(other.re), self.im.max(other.im)),
+         BinaryOp::Pow => self.powc(other),
+      }
+   }
+
+   fn unary(self, op: UnaryOp) -> Self {
+      match op {
+         UnaryOp::Abs => Complex::new(self.norm(), 0.0),
+         UnaryOp::Exp => self.exp(),
+         UnaryOp::Log => self.ln(),
+         UnaryOp::DbToLinear => (self * (LN_10 / 20.0)).exp(),
+         UnaryOp::MidiToHz => ((self - 69.0) * (LN_2 / 12.0)).exp() * 440.0,
+      }
+   }
+
+   fn from_value(x: Value) -> Self { unwrap_match!(x, Value::C32(x) => Complex::new(x.re.into(), x.im.into())) }
+
+   fn into_value(self) -> Value { Value::C32(Complex::new(self.re.into(), self.im.into())) }
+}
+
+impl Arithmetic for u32 {
+   fn binary(self, op: BinaryOp, other: Self) -> Self {
+      match op {
+         BinaryOp::Add => self.wrapping_add(other),
+         BinaryOp::Subtract => self.wrapping_sub(other),
+         BinaryOp::Multiply => self.wrapping_mul(other),
+         BinaryOp::Divide => self.checked_div(other).unwrap_or(0),
+         BinaryOp::Min => self.min(other),
+         BinaryOp::Max => self.max(other),
+         BinaryOp::Pow => self.wrapping_pow(other),
+      }
+   }
+
+   fn unary(self, op: UnaryOp) -> Self {
+      match op {
+         UnaryOp::Abs => self,
+         op => unary_f64(op, self as f64).round() as u32,
+      }
+   }
+
+   fn from_value(x: Value) -> Self { unwrap_match!(x, Value::U32(x) => x) }
+
+   fn into_value(self) -> Value { Value::U32(self) }
+}
+
+impl Arithmetic for i32 {
+   fn binary(self, op: BinaryOp, other: Self) -> Self {
+      match op {
+         BinaryOp::Add => self.wrapping_add(other),
+         BinaryOp::Subtract => self.wrapping_sub(other),
+         BinaryOp::Multiply => self.wrapping_mul(other),
+         BinaryOp::Divide => self.checked_div(other).unwrap_or(0),
+         BinaryOp::Min => self.min(other),
+         BinaryOp::Max => self.max(other),
+         BinaryOp::Pow if other >= 0 => self.wrapping_pow(other as u32),
+         BinaryOp::Pow => (self as f64).powi(other).round() as i32,
+      }
+   }
+
+   fn unary(self, op: UnaryOp) -> Self {
+      match op {
+         UnaryOp::Abs => self.wrapping_abs(),
+         op => unary_f64(op, self as f64).round() as i32,
+      }
+   }
+
+   fn from_value(x: Value) -> Self { unwrap_match!(x, Value::I32(x) => x) }
+
+   fn into_value(self) -> Value { Value::I32(self) }
+}
+
+fn clamp<T: Arithmetic>(x: T, min: T, max: T) -> T { x.binary(BinaryOp::Max, min).binary(BinaryOp::Min, max) }
+
+// Calls `$f::<T>` with the sample type `T` of `$ty`.
+macro_rules! for_type {
+   ($ty:expr, $f:ident($($arg:expr),*)) => {
+      match $ty {
+         PrimType::F32 => $f::<f32>($($arg),*),
+         PrimType::C32 => $f::<Complex<f32>>($($arg),*),
+         PrimType::U32 => $f::<u32>($($arg),*),
+         PrimType::I32 => $f::<i32>($($arg),*),
+      }
+   }
+}
+
+pub(super) fn binary_value(op: BinaryOp, x: Value, y: Value) -> Value {
+   fn binary<T: Arithmetic>(op: BinaryOp, x: Value, y: Value) -> Value { T::from_value(x).binary(op, T::from_value(y)).into_value() }
+   for_type!(x.Type(), binary(op, x, y))
+}
+
+pub(super) fn unary_value(op: UnaryOp, x: Value) -> Value {
+   fn unary<T: Arithmetic>(op: UnaryOp, x: Value) -> Value { T::from_value(x).unary(op).into_value() }
+   for_type!(x.Type(), unary(op, x))
+}
+
+pub(super) fn clamp_value(x: Value, min: Value, max: Value) -> Value {
+   fn clamp_native<T: Arithmetic>(x: Value, min: Value, max: Value) -> Value {
+      clamp(T::from_value(x), T::from_value(min), T::from_value(max)).into_value()
+   }
+   for_type!(x.Type(), clamp_native(x, min, max))
+}
+
+pub(super) fn zero(ty: PrimType) -> Value { super::cast_value(Value::U32(0), ty) }
+
+pub(super) fn one(ty: PrimType) -> Value { super::cast_value(Value::U32(1), ty) }
+
+// Runs `$body` with the constant `$name` set to `$op`, one match arm per operation, so that
+// closures using it capture nothing and the kernels called with them are compiled with the
+// operation known.
+macro_rules! with_op {
+   (const $name:ident: BinaryOp = $op:expr, $body:expr) => {
+      with_op!($name, $op, $body, BinaryOp{Add, Subtract, Multiply, Divide, Min, Max, Pow})
+   };
+   (const $name:ident: UnaryOp = $op:expr, $body:expr) => {
+      with_op!($name, $op, $body, UnaryOp{Abs, Exp, Log, DbToLinear, MidiToHz})
+   };
+   ($name:ident, $op:expr, $body:expr, $enum:ident{$($variant:ident),*}) => {
+      match $op {
+         $( $enum::$variant => { const $name: $enum = $enum::$variant; $body } )*
+      }
+   };
+}
+
+macro_rules! impl_for_each_sample {
+   ($($prim_type:ident),*) => {
+      fn combine(op: BinaryOp, x0: &GenericSampledBuffer, x1: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match (x0, x1, y) {
+            $( (GenericSampledBuffer::$prim_type(x0), GenericSampledBuffer::$prim_type(x1), GenericSampledBuffer::$prim_type(y)) =>
+               with_op!(const OP: BinaryOp = op, simd::zip(&mut y.samples, &x0.samples, &x1.samples, |x0, x1| x0.binary(OP, x1))), )*
+            _ => unreachable!(),
+         }
+      }
+
+      fn accumulate(op: BinaryOp, x: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match (x, y) {
+            $( (GenericSampledBuffer::$prim_type(x), GenericSampledBuffer::$prim_type(y)) =>
+               with_op!(const OP: BinaryOp = op, simd::update(&mut y.samples, &x.samples, |y, x| y.binary(OP, x))), )*
+            _ => unreachable!(),
+         }
+      }
+
+      fn map(op: UnaryOp, x: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match (x, y) {
+            $( (GenericSampledBuffer::$prim_type(x), GenericSampledBuffer::$prim_type(y)) =>
+               with_op!(const OP: UnaryOp = op, simd::map(&mut y.samples, &x.samples, |x| x.unary(OP))), )*
+            _ => unreachable!(),
+         }
+      }
+
+      fn clamp_between(x: &GenericSampledBuffer, min: &GenericSampledBuffer, max: &GenericSampledBuffer, y: &mut GenericSampledBuffer) {
+         match (x, min, max, y) {
+            $( (GenericSampledBuffer::$prim_type(x), GenericSampledBuffer::$prim_type(min), GenericSampledBuffer::$prim_type(max),
+                GenericSampledBuffer::$prim_type(y)) =>
+               simd::zip3(&mut y.samples, &x.samples, &min.samples, &max.samples, clamp), )*
+            _ => unreachable!(),
+         }
+      }
+
+      // Copies the samples of `x` where `index` picks it.
+      fn select(index: &[u32], selected: u32, x: &Buffer, y: &mut GenericSampledBuffer) {
+         match y {
+            $( GenericSampledBuffer::$prim_type(y) => {
+               let x = unwrap_match!(x, Buffer::Sampled(GenericSampledBuffer::$prim_type(x)) => &x.samples);
+               y.samples.iter_mut().zip(x).zip(index).for_each(|((y, &x), &index)| if index == selected { *y = x });
+            } )*
+         }
+      }
+   }
+}
+enumerate_prim_types!{impl_for_each_sample}
+
+// Applies `op` sample by sample to two streams of the same type.
+pub struct Binary {
+   op: BinaryOp,
+}
+impl Binary {
+   pub(super) fn new(op: BinaryOp) -> Self { Self{op} }
+}
+impl PrimElementProcessor for Binary {
    fn compute_outplace(
-      &mut self, output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>,
+      &mut self, output: &mut Outputs, input: &Inputs,
       buffer_sz: usize, _: &FlowStore,
    ) {
-      if let Some(y) = output.get_mut(&OutputNo(0)) {
-         let y = unwrap_match!(&mut **y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y);
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
          y.update_size(buffer_sz);
 
-         if let (Some(x0), Some(x1)) = (input.get(&InputNo(0)), input.get(&InputNo(1))) {
-            let x0 = unwrap_match!(&**x0, Buffer::Sampled(GenericSampledBuffer::F32(x0)) => x0);
-            let x1 = unwrap_match!(&**x1, Buffer::Sampled(GenericSampledBuffer::F32(x1)) => x1);
-
-            y.samples.iter_mut().zip(x0.samples.iter().zip(&x1.samples)).for_each(|(y,(x0,x1))| *y = *x0 * *x1);
+         if let (Some(x0), Some(x1)) = (input.get(InputNo(0)), input.get(InputNo(1))) {
+            let x0 = unwrap_match!(&*x0, Buffer::Sampled(x0) => x0);
+            let x1 = unwrap_match!(&*x1, Buffer::Sampled(x1) => x1);
+            combine(self.op, x0, x1, y);
          }
          else { y.clear(); }
       }
    }
 }
 
-pub struct AddF32;
-impl AddF32 {
-   pub(super) fn new() -> Self { Self }
+pub struct Unary {
+   op: UnaryOp,
+}
+impl Unary {
+   pub(super) fn new(op: UnaryOp) -> Self { Self{op} }
 }
-impl PrimElementProcessor for AddF32 {
+impl PrimElementProcessor for Unary {
    fn compute_outplace(
-      &mut self, output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>,
+      &mut self, output: &mut Outputs, input: &Inputs,
       buffer_sz: usize, _: &FlowStore,
    ) {
-      if let Some(y) = output.get_mut(&OutputNo(0)) {
-         let y = unwrap_match!(&mut **y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y);
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
          y.update_size(buffer_sz);
 
-         if let (Some(x0), Some(x1)) = (input.get(&InputNo(0)), input.get(&InputNo(1))) {
-            let x0 = unwrap_match!(&**x0, Buffer::Sampled(GenericSampledBuffer::F32(x0)) => x0);
-            let x1 = unwrap_match!(&**x1, Buffer::Sampled(GenericSampledBuffer::F32(x1)) => x1);
+         if let Some(x) = input.get(InputNo(0)) {
+            map(self.op, unwrap_match!(&*x, Buffer::Sampled(x) => x), y);
+         }
+         else { y.clear(); }
+      }
+   }
+}
+
+pub struct Clamp;
+impl PrimElementProcessor for Clamp {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
 
-            y.samples.iter_mut().zip(x0.samples.iter().zip(&x1.samples)).for_each(|(y,(x0,x1))| *y = *x0 + *x1);
+         if let (Some(x), Some(min), Some(max)) = (input.get(InputNo(0)), input.get(InputNo(1)), input.get(InputNo(2))) {
+            let x = unwrap_match!(&*x, Buffer::Sampled(x) => x);
+            let min = unwrap_match!(&*min, Buffer::Sampled(min) => min);
+            let max = unwrap_match!(&*max, Buffer::Sampled(max) => max);
+            clamp_between(x, min, max, y);
          }
          else { y.clear(); }
       }
    }
-}
\ No newline at end of file
+}
+
+// Sums any number of streams, skipping unconnected inputs.
+pub struct Mix {
+   inputs: u32,
+}
+impl Mix {
+   pub(super) fn new(inputs: u32) -> Self { Self{inputs} }
+}
+impl PrimElementProcessor for Mix {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+         y.clear();
+
+         (0..self.inputs).filter_map(|no| input.get(InputNo(no))).for_each(|x| {
+            accumulate(BinaryOp::Add, unwrap_match!(&*x, Buffer::Sampled(x) => x), y);
+         });
+      }
+   }
+}
+
+// Passes on, sample by sample, the input picked by the index on input 0. Indices without a
+// connected input yield zero.
+pub struct Select {
+   inputs: u32,
+}
+impl Select {
+   pub(super) fn new(inputs: u32) -> Self { Self{inputs} }
+}
+impl PrimElementProcessor for Select {
+   fn compute_outplace(
+      &mut self, output: &mut Outputs, input: &Inputs,
+      buffer_sz: usize, _: &FlowStore,
+   ) {
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(y) => y);
+         y.update_size(buffer_sz);
+         y.clear();
+
+         if let Some(index) = input.get(InputNo(0)) {
+            let index = unwrap_match!(&*index, Buffer::Sampled(GenericSampledBuffer::U32(index)) => index);
+            (1..=self.inputs).filter_map(|no| Some((no - 1, input.get(InputNo(no))?))).for_each(|(selected, x)| select(&index.samples, selected, &x, y));
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::super::{super::{Store, EdgeMode, Type, element::Element}, PrimElement};
+   use super::*;
+   use linear_map::LinearMap;
+
+   fn f(x: f32) -> Value { Value::F32(x.into()) }
+
+   fn c(re: f32, im: f32) -> Value { Value::C32(Complex::new(re.into(), im.into())) }
+
+   fn buffer(ty: PrimType, xs: &[Value]) -> Buffer {
+      let mut buffer = Buffer::new(Type::Sampled{ty, f_nyq: 1000});
+      match &mut buffer {
+         Buffer::Sampled(GenericSampledBuffer::F32(x)) => x.samples = xs.iter().map(|&x| f32::from_value(x)).collect(),
+         Buffer::Sampled(GenericSampledBuffer::C32(x)) => x.samples = xs.iter().map(|&x| Complex::from_value(x)).collect(),
+         Buffer::Sampled(GenericSampledBuffer::U32(x)) => x.samples = xs.iter().map(|&x| u32::from_value(x)).collect(),
+         Buffer::Sampled(GenericSampledBuffer::I32(x)) => x.samples = xs.iter().map(|&x| i32::from_value(x)).collect(),
+         _ => unreachable!(),
+      }
+      buffer
+   }
+
+   fn values(buffer: &Buffer) -> Vec<Value> {
+      match buffer {
+         Buffer::Sampled(GenericSampledBuffer::F32(x)) => x.samples.iter().map(|x| x.into_value()).collect(),
+         Buffer::Sampled(GenericSampledBuffer::C32(x)) => x.samples.iter().map(|x| x.into_value()).collect(),
+         Buffer::Sampled(GenericSampledBuffer::U32(x)) => x.samples.iter().map(|x| x.into_value()).collect(),
+         Buffer::Sampled(GenericSampledBuffer::I32(x)) => x.samples.iter().map(|x| x.into_value()).collect(),
+         _ => unreachable!(),
+      }
+   }
+
+   // Runs `element`, fed with one input of the flow per stream of `xs`.
+   fn render(element: PrimElement, ty: PrimType, xs: &[Vec<Value>]) -> Vec<Value> {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let node = store.add_element(flow, Element::Prim(element)).unwrap();
+      let mut buffers: Vec<_> = xs.iter().map(|x| buffer(ty, x)).collect();
+      let input: LinearMap<_, &mut Buffer> = buffers.iter_mut().enumerate().map(|(no, x)| {
+         let (input_no, input) = store.add_input(flow, "", Type::Sampled{ty, f_nyq: 1000}).unwrap();
+         store.add_edge(flow, input, OutputNo(0), node, InputNo(no as u32), EdgeMode::Exact).unwrap();
+         (input_no, x)
+      }).collect();
+      let (output_no, output) = store.add_output(flow, "", Type::Sampled{ty, f_nyq: 1000}).unwrap();
+      store.add_edge(flow, node, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let mut y = Buffer::new(Type::Sampled{ty, f_nyq: 1000});
+      let mut output: LinearMap<_, &mut Buffer> = vec![(output_no, &mut y)].into_iter().collect();
+      store.compute_outplace(flow, &mut output, &input, xs[0].len());
+      drop(output);
+      values(&y)
+   }
+
+   #[test]
+   fn binary_values_follow_the_arithmetic_of_their_type() {
+      let (nan, inf) = (f32::NAN, f32::INFINITY);
+      let table = [
+         (BinaryOp::Add, Value::I32(i32::MAX), Value::I32(1), Value::I32(i32::MIN)),
+         (BinaryOp::Subtract, Value::U32(0), Value::U32(1), Value::U32(u32::MAX)),
+         (BinaryOp::Multiply, Value::I32(1 << 16), Value::I32(1 << 16), Value::I32(0)),
+         (BinaryOp::Divide, Value::I32(7), Value::I32(0), Value::I32(0)),
+         (BinaryOp::Divide, Value::I32(i32::MIN), Value::I32(-1), Value::I32(0)),
+         (BinaryOp::Divide, Value::U32(7), Value::U32(2), Value::U32(3)),
+         (BinaryOp::Divide, Value::U32(7), Value::U32(0), Value::U32(0)),
+         (BinaryOp::Pow, Value::U32(2), Value::U32(32), Value::U32(0)),
+         (BinaryOp::Pow, Value::I32(-3), Value::I32(3), Value::I32(-27)),
+         (BinaryOp::Pow, Value::I32(2), Value::I32(-2), Value::I32(0)),
+         (BinaryOp::Min, Value::I32(-1), Value::I32(1), Value::I32(-1)),
+         (BinaryOp::Max, Value::U32(1), Value::U32(2), Value::U32(2)),
+         (BinaryOp::Divide, f(1.0), f(0.0), f(inf)),
+         (BinaryOp::Divide, f(0.0), f(0.0), f(nan)),
+         (BinaryOp::Add, f(nan), f(1.0), f(nan)),
+         (BinaryOp::Min, f(nan), f(1.0), f(1.0)),
+         (BinaryOp::Max, f(2.0), f(nan), f(2.0)),
+         (BinaryOp::Pow, f(2.0), f(-1.0), f(0.5)),
+         (BinaryOp::Multiply, c(0.0, 1.0), c(0.0, 1.0), c(-1.0, 0.0)),
+         (BinaryOp::Min, c(1.0, -1.0), c(-1.0, 1.0), c(-1.0, -1.0)),
+         (BinaryOp::Max, c(1.0, -1.0), c(-1.0, 1.0), c(1.0, 1.0)),
+      ];
+      for &(op, x, y, expected) in table.iter() {
+         assert_eq!(binary_value(op, x, y), expected, "{:?} {:?} {:?}", op, x, y);
+      }
+   }
+
+   #[test]
+   fn unary_values_follow_the_arithmetic_of_their_type() {
+      let table = [
+         (UnaryOp::Abs, Value::I32(-5), Value::I32(5)),
+         (UnaryOp::Abs, Value::I32(i32::MIN), Value::I32(i32::MIN)),
+         (UnaryOp::Abs, Value::U32(5), Value::U32(5)),
+         (UnaryOp::Abs, f(-0.5), f(0.5)),
+         (UnaryOp::Abs, c(3.0, -4.0), c(5.0, 0.0)),
+         (UnaryOp::Exp, Value::I32(0), Value::I32(1)),
+         (UnaryOp::Exp, Value::I32(100), Value::I32(i32::MAX)),
+         (UnaryOp::Log, Value::U32(0), Value::U32(0)),
+         (UnaryOp::Log, f(-1.0), f(f32::NAN)),
+         (UnaryOp::Log, f(0.0), f(f32::NEG_INFINITY)),
+         (UnaryOp::DbToLinear, Value::I32(20), Value::I32(10)),
+         (UnaryOp::DbToLinear, f(-20.0), f(0.1)),
+         (UnaryOp::MidiToHz, Value::U32(69), Value::U32(440)),
+         (UnaryOp::MidiToHz, f(81.0), f(880.0)),
+         (UnaryOp::MidiToHz, c(69.0, 0.0), c(440.0, 0.0)),
+      ];
+      for &(op, x, expected) in table.iter() {
+         assert_eq!(unary_value(op, x), expected, "{:?} {:?}", op, x);
+      }
+   }
+
+   #[test]
+   fn clamp_values_let_the_maximum_win_over_the_minimum() {
+      let table = [
+         (Value::I32(5), Value::I32(0), Value::I32(10), Value::I32(5)),
+         (Value::I32(-5), Value::I32(0), Value::I32(10), Value::I32(0)),
+         (Value::U32(50), Value::U32(0), Value::U32(10), Value::U32(10)),
+         (Value::I32(5), Value::I32(10), Value::I32(0), Value::I32(0)),
+         (f(0.5), f(1.0), f(-1.0), f(-1.0)),
+         (f(f32::NAN), f(-1.0), f(1.0), f(-1.0)),
+         (f(0.5), f(f32::NAN), f(f32::NAN), f(0.5)),
+         (c(2.0, -2.0), c(-1.0, -1.0), c(1.0, 1.0), c(1.0, -1.0)),
+      ];
+      for &(x, min, max, expected) in table.iter() {
+         assert_eq!(clamp_value(x, min, max), expected, "{:?} {:?} {:?}", x, min, max);
+      }
+   }
+
+   // The elements run the operations through the SIMD kernels, so they are checked against the
+   // helpers `fold` uses on odd lengths.
+   #[test]
+   fn elements_agree_with_the_folded_values() {
+      let streams = [
+         (PrimType::I32, vec![i32::MAX, i32::MIN, -1, 0, 1, 7, -7, 1 << 16, 3, 2, -3].into_iter().map(Value::I32).collect::<Vec<_>>()),
+         (PrimType::U32, vec![u32::MAX, 0, 1, 2, 7, 32, 69, 1 << 16, 3, 5, 11].into_iter().map(Value::U32).collect()),
+         (PrimType::F32, vec![f32::NAN, f32::INFINITY, -0.0, 0.0, 1.0, -1.0, 0.5, 69.0, -20.0, 1e30, -3.0].into_iter().map(f).collect()),
+         (PrimType::C32, vec![c(0.0, 1.0), c(3.0, -4.0), c(0.0, 0.0), c(-1.0, 0.5), c(69.0, 0.0), c(1.0, 1.0), c(0.5, -2.0)]),
+      ];
+      let ops = [BinaryOp::Add, BinaryOp::Subtract, BinaryOp::Multiply, BinaryOp::Divide, BinaryOp::Min, BinaryOp::Max, BinaryOp::Pow];
+      let unary_ops = [UnaryOp::Abs, UnaryOp::Exp, UnaryOp::Log, UnaryOp::DbToLinear, UnaryOp::MidiToHz];
+      for (ty, x) in streams.iter() {
+         let (ty, mut y, mut z) = (*ty, x.clone(), x.clone());
+         y.rotate_left(1);
+         z.rotate_left(2);
+         for &op in ops.iter() {
+            let expected: Vec<_> = x.iter().zip(&y).map(|(&x, &y)| binary_value(op, x, y)).collect();
+            assert_eq!(render(PrimElement::Binary{op, ty, f_nyq: 1000}, ty, &[x.clone(), y.clone()]), expected, "{:?} {:?}", op, ty);
+         }
+         for &op in unary_ops.iter() {
+            let expected: Vec<_> = x.iter().map(|&x| unary_value(op, x)).collect();
+            assert_eq!(render(PrimElement::Unary{op, ty, f_nyq: 1000}, ty, std::slice::from_ref(x)), expected, "{:?} {:?}", op, ty);
+         }
+         let expected: Vec<_> = x.iter().zip(&y).zip(&z).map(|((&x, &y), &z)| clamp_value(x, y, z)).collect();
+         assert_eq!(render(PrimElement::Clamp{ty, f_nyq: 1000}, ty, &[x.clone(), y.clone(), z.clone()]), expected, "{:?}", ty);
+      }
+   }
+
+   #[test]
+   fn select_passes_on_the_picked_input() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let (_, index) = store.add_input(flow, "index", Type::Sampled{ty: PrimType::U32, f_nyq: 1000}).unwrap();
+      let (output_no, output) = store.add_output(flow, "out", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      let select = store.add_element(flow, Element::Prim(PrimElement::Select{ty: PrimType::F32, f_nyq: 1000, inputs: 3})).unwrap();
+      store.add_edge(flow, index, OutputNo(0), select, InputNo(0), EdgeMode::Exact).unwrap();
+      for (no, x) in [(1, 1.0), (3, 3.0)].iter().copied() {
+         let constant = store.add_element(flow, Element::Prim(PrimElement::Constant{value: Value::F32(x.into()), f_nyq: 1000})).unwrap();
+         store.add_edge(flow, constant, OutputNo(0), select, InputNo(no), EdgeMode::Exact).unwrap();
+      }
+      store.add_edge(flow, select, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let mut index = Buffer::new(Type::Sampled{ty: PrimType::U32, f_nyq: 1000});
+      unwrap_match!(&mut index, Buffer::Sampled(GenericSampledBuffer::U32(index)) => index.samples = vec![0, 1, 2, 3, 0]);
+      let mut y = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+      let input: LinearMap<_, &mut Buffer> = vec![(InputNo(0), &mut index)].into_iter().collect();
+      let mut output: LinearMap<_, &mut Buffer> = vec![(output_no, &mut y)].into_iter().collect();
+      store.compute_outplace(flow, &mut output, &input, 5);
+      drop(output);
+      assert_eq!(unwrap_match!(y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y.samples), vec![1.0, 0.0, 3.0, 0.0, 1.0]);
+   }
+}
@@ -2,10 +2,8 @@ use super::PrimElementProcessor;
 
 use super::{
    InputNo, OutputNo, super::flow_store::FlowStore,
-   super::processor::{Buffer, GenericSampledBuffer},
+   super::processor::{Buffer, GenericSampledBuffer, Inputs, Outputs},
 };
-use linear_map::LinearMap;
-use std::cell::RefMut;
 
 pub struct SineOscF32 {
    current_phase: f32,
@@ -18,15 +16,15 @@ impl SineOscF32 {
 }
 impl PrimElementProcessor for SineOscF32 {
    fn compute_outplace(
-      &mut self, output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>,
+      &mut self, output: &mut Outputs, input: &Inputs,
       buffer_sz: usize, _: &FlowStore,
    ) {
-      if let Some(y) = output.get_mut(&OutputNo(0)) {
-         let y = unwrap_match!(&mut **y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y);
+      if let Some(mut y) = output.get_mut(OutputNo(0)) {
+         let y = unwrap_match!(&mut *y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y);
          y.update_size(buffer_sz);
 
-         if let Some(f) = input.get(&InputNo(0)) {
-            let f = unwrap_match!(&**f, Buffer::Sampled(GenericSampledBuffer::F32(f)) => f);
+         if let Some(f) = input.get(InputNo(0)) {
+            let f = unwrap_match!(&*f, Buffer::Sampled(GenericSampledBuffer::F32(f)) => f);
 
             y.samples.iter_mut().zip(&f.samples).for_each(|(y,f)| {
                self.current_phase += f / self.fs as f32;
@@ -1,52 +1,56 @@
 use super::{
-   flow::{Flow, Node, EdgeIx},
+   flow::{Flow, Node, NodeIx},
    Type, PrimType, Value,
    flow_store::{FlowStore, FlowId},
-   prim_element::{PrimElement, PrimElementProcessor, mk_prim_element_processor},
+   element::Element,
+   prim_element::{PrimElement, BoxedPrimElementProcessor, mk_prim_element_processor},
+   template::Substitution,
    OutputNo, InputNo
 };
 
 use intmap::IntMap;
-use std::cell::{RefCell, RefMut};
+use atomic_refcell::{AtomicRefCell, AtomicRef, AtomicRefMut};
+use std::cell::Cell;
 use linear_map::LinearMap;
-use std::ops::{Deref, DerefMut};
-use std::collections::HashMap;
+use std::ops::{Deref, DerefMut, Range};
+use rayon::prelude::*;
+
+// Stages that process fewer samples than this run on the calling thread, where they take less
+// time than handing their tasks to workers.
+const PARALLEL_WORK: usize = 1 << 13;
 
 pub struct ProcessorStore {
    processors: IntMap<Processor>,
-   prim_element_processors: HashMap<PrimElement, (Box<RefCell<dyn PrimElementProcessor + Send>>, u32)>,
 }
 impl ProcessorStore {
-   pub(super) fn new() -> Self{ Self{processors: IntMap::new(), prim_element_processors: HashMap::new()} }
+   pub(super) fn new() -> Self{ Self{processors: IntMap::new()} }
 
    pub(super) fn compute_outplace<BufferRef, BufferRefMut>(
       &self, flow_id: FlowId, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRef>, buffer_sz: usize,
-      flow_store: &FlowStore,
+      flow_store: &FlowStore, workers: Option<&rayon::ThreadPool>,
    ) where BufferRef: Deref<Target=Buffer>, BufferRefMut: DerefMut<Target=Buffer>
    {
       let flow = &flow_store[flow_id];
       let processor = self.processor(flow_id);
-      processor.compute_outplace(output, input, buffer_sz, flow, flow_store, self)
-   }
+      let block = Block{f_nyq: processor.f_nyq, start: processor.clock.get(), len: buffer_sz};
+      Processor::check_buffer_types(input, flow.input_types());
+      Processor::check_buffer_types(output, flow.output_types());
+      Processor::check_buffer_sz(input, flow.input_types(), &block);
 
-   pub(super) fn compute_outplace_prim(
-      &self, prim_element_id: PrimElement,
-      output: &mut LinearMap<OutputNo, RefMut<Buffer>>, input: &LinearMap<InputNo, RefMut<Buffer>>,
-      buffer_sz: usize, flow_store: &FlowStore,
-   ) {
-      self.prim_element_processors[&prim_element_id].0.borrow_mut().compute_outplace(output, input, buffer_sz, flow_store);
+      processor.compute_outplace(output, input, &block, flow_store, workers);
+      processor.clock.set(block.start + buffer_sz as u64);
    }
 
    pub fn processor(&self, flow_id: FlowId) -> &Processor {
       self.processors.get(flow_id.0).unwrap()
    }
 
-   pub(super) fn processor_mut(&mut self, flow_id: FlowId) -> &mut Processor {
-      self.processors.get_mut(flow_id.0).unwrap()
+   pub(super) fn add(&mut self, flow_id: FlowId) {
+      self.insert(flow_id, Processor::new(flow_id))
    }
 
-   pub(super) fn add(&mut self, flow_id: FlowId) {
-      let result = self.processors.insert(flow_id.0, Processor::new());
+   pub(super) fn insert(&mut self, flow_id: FlowId, processor: Processor) {
+      let result = self.processors.insert(flow_id.0, processor);
       assert!(result);
    }
 
@@ -54,107 +58,335 @@ impl ProcessorStore {
       self.processors.remove(flow_id.0).unwrap()
    }
 
-   pub(super) fn use_prim_element_processor(&mut self, prim_element_id: PrimElement) {
-      if let Some(pep) = self.prim_element_processors.get_mut(&prim_element_id) {
-         pep.1 += 1;
-      }
-      else {
-         self.prim_element_processors.insert(prim_element_id, (mk_prim_element_processor(prim_element_id), 0));
-      }
+   // Every processor whose schedule runs `flow_id`, with the paths of the nodes that
+   // instantiate it, in an order that only depends on the graphs.
+   pub(super) fn instances_mut(&mut self, flow_id: FlowId, flow_store: &FlowStore) -> Vec<(&mut Processor, Vec<Vec<NodeIx>>)> {
+      let mut roots: Vec<_> = self.processors.iter_mut().collect();
+      roots.sort_by_key(|(&id, _)| id);
+
+      roots.into_iter().filter_map(|(_, processor)| {
+         let paths = instance_paths(processor.flow_id, flow_id, flow_store);
+         if paths.is_empty() { None } else { Some((processor, paths)) }
+      }).collect()
    }
 
-   pub(super) fn free_prim_element_processor(&mut self, prim_element_id: PrimElement) {
-      let rc = &mut self.prim_element_processors.get_mut(&prim_element_id).unwrap().1;
-      if *rc == 0 {
-         self.prim_element_processors.remove(&prim_element_id);
-      }
-      else {
-         *rc -= 1;
-      }
+   pub(super) fn build(flow_store: &FlowStore) -> Self {
+      let mut processors = Self::new();
+      flow_store.ids().for_each(|flow_id| processors.insert(flow_id, Processor::build(flow_id, flow_store)));
+      processors
+   }
+
+   pub(super) fn adopt_state(&mut self, old: &mut ProcessorStore) {
+      self.processors.iter_mut().for_each(|(&id, processor)|
+         if let Some(old_processor) = old.processors.get_mut(id) {
+            processor.adopt_state(old_processor);
+         }
+      )
    }
 }
 
+fn instance_paths(flow_id: FlowId, target: FlowId, flow_store: &FlowStore) -> Vec<Vec<NodeIx>> {
+   if flow_id == target { return vec![Vec::new()] }
+   flow_store.get(flow_id).into_iter().flat_map(Flow::nodes).flat_map(|(node_ix, node)| match node {
+      Node::Element(element) => element.flow_id().into_iter().flat_map(|sub_flow_id| instance_paths(sub_flow_id, target, flow_store))
+         .map(|mut path| { path.insert(0, node_ix); path })
+         .collect(),
+      _ => Vec::new(),
+   }).collect()
+}
+
+fn join(prefix: &[NodeIx], node_ix: NodeIx) -> Vec<NodeIx> {
+   prefix.iter().copied().chain(std::iter::once(node_ix)).collect()
+}
+
+// Runs a flow with all nested flows inlined into a single schedule. The state of every
+// primitive element is kept by its path, the `NodeIx`s leading to it through nested flows,
+// sorted so that the states below any node are contiguous. The schedule is cut into stages
+// run one after another, each made of tasks that touch disjoint slots and elements, so that
+// the tasks of a stage may run concurrently.
 pub struct Processor {
-   buffers: IntMap<RefCell<Buffer>>,
+   flow_id: FlowId,
+   f_nyq: Option<u64>,
+   clock: Cell<u64>,
+   slots: Vec<AtomicRefCell<Buffer>>,
+   schedule: Vec<Step>,
+   stages: Vec<Vec<Range<usize>>>,
+   delays: Vec<Delay>,
+   prims: Vec<PrimInstance>,
+   optimizations: Optimizations,
+}
+
+// Per-channel elements have one processor for every channel of their buses, the others a
+// single one that sees whole buffers.
+struct PrimInstance {
+   path: Vec<NodeIx>,
+   element: PrimElement,
+   per_channel: bool,
+   processors: Vec<BoxedPrimElementProcessor>,
+}
+
+// Elements that are compiled but never run, by their paths: those whose outputs are constant,
+// with their values, and the others no output depends on.
+#[derive(Clone, Default, Debug)]
+pub struct Optimizations {
+   pub pruned: Vec<Vec<NodeIx>>,
+   pub folded: Vec<(Vec<NodeIx>, LinearMap<OutputNo, Value>)>,
+}
+
+// The state below a node, detached while the node is removed from the flow. Paths are
+// relative to the flow instance the node was removed from.
+pub(super) struct NodeProcessor {
+   prims: Vec<PrimInstance>,
+   delays: Vec<(Vec<NodeIx>, OutputNo, Type, Buffer)>,
 }
+
+// The span of time computed by one call, as `len` samples at the reference rate `f_nyq`.
+// Streams at other rates get proportionally sized blocks, which add up without drift.
+#[derive(Clone, Copy)]
+pub(super) struct Block {
+   f_nyq: Option<u64>,
+   start: u64,
+   len: usize,
+}
+impl Block {
+   fn size(&self, f_nyq: Option<u64>) -> usize {
+      self.position(f_nyq, self.len as u64)
+   }
+
+   // The number of samples a stream at `f_nyq` has in the first `time` samples of the block.
+   fn position(&self, f_nyq: Option<u64>, time: u64) -> usize {
+      samples_at(self.f_nyq, f_nyq, self.start + time) - samples_at(self.f_nyq, f_nyq, self.start)
+   }
+}
+
+// The number of samples a stream at `f_nyq` has had once `time` samples have passed at the
+// `reference` rate.
+pub(super) fn samples_at(reference: Option<u64>, f_nyq: Option<u64>, time: u64) -> usize {
+   match (reference, f_nyq) {
+      (Some(reference), Some(f_nyq)) if reference != f_nyq => (time as u128 * f_nyq as u128 / reference as u128) as usize,
+      _ => time as usize,
+   }
+}
+
+pub(super) fn rate(ty: Type) -> Option<u64> {
+   option_match!(ty, Type::Sampled{f_nyq, ..} | Type::Bus{f_nyq, ..} => f_nyq)
+}
+
+// One entry of the compiled schedule. Edges carry no storage of their own: every connected
+// output port writes to a slot that all its readers share, and slots are reused once their
+// last reader has run. The Input and Output nodes of nested flows only forward slots.
+enum Step {
+   Input{no: InputNo, f_nyq: Option<u64>, slot: usize},
+   Fill{slot: usize, f_nyq: Option<u64>, value: Option<Value>},
+   Mix{slot: usize, sources: Vec<usize>},
+   Prim{prim: usize, f_nyq: Option<u64>, inputs: Vec<(InputNo, usize)>, outputs: Vec<(OutputNo, usize)>},
+   Output{no: OutputNo, f_nyq: Option<u64>, sources: Vec<usize>, default: Option<Value>},
+}
+
+// Holds the previous block of an output port read by feedback edges. Its slot is never
+// reused, and is refreshed from the port's `source` slot once the schedule has run.
+struct Delay {
+   port: (Vec<NodeIx>, OutputNo),
+   ty: Type,
+   slot: usize,
+   source: usize,
+}
+
 impl Processor {
-   pub(super) fn new() -> Self {
-      Self{buffers: IntMap::new()}
+   pub(super) fn new(flow_id: FlowId) -> Self {
+      Self{
+         flow_id, f_nyq: None, clock: Cell::new(0), slots: Vec::new(), schedule: Vec::new(), stages: Vec::new(), delays: Vec::new(), prims: Vec::new(),
+         optimizations: Optimizations::default(),
+      }
+   }
+
+   fn build(flow_id: FlowId, flow_store: &FlowStore) -> Self {
+      let mut processor = Self::new(flow_id);
+      flow_store[flow_id].nodes().for_each(|(node_ix, node)| processor.add_node(&[], node_ix, node, flow_store));
+      processor.compile(flow_store);
+      processor
+   }
+
+   fn adopt_state(&mut self, old: &mut Processor) {
+      if old.flow_id != self.flow_id { return }
+      self.clock.set(old.clock.get());
+      self.prims.iter_mut().for_each(|prim|
+         if let Ok(ix) = old.prims.binary_search_by(|old_prim| old_prim.path.cmp(&prim.path)) {
+            let old_prim = &mut old.prims[ix];
+            if old_prim.element == prim.element && old_prim.per_channel == prim.per_channel {
+               prim.processors.iter_mut().zip(&mut old_prim.processors).for_each(|(processor, old_processor)| std::mem::swap(processor, old_processor));
+            }
+         }
+      );
+      self.delays.iter().for_each(|delay|
+         if let Some(old_delay) = old.delays.iter().find(|old_delay| old_delay.port == delay.port && old_delay.ty == delay.ty) {
+            std::mem::swap(&mut *self.slots[delay.slot].borrow_mut(), &mut *old.slots[old_delay.slot].borrow_mut());
+         }
+      )
+   }
+
+   pub(super) fn add_node(&mut self, prefix: &[NodeIx], node_ix: NodeIx, node: &Node, flow_store: &FlowStore) {
+      let substitution = self.substitution(prefix, flow_store);
+      self.add_instance_node(prefix, node_ix, &substitution.node(node_ix, node), flow_store)
+   }
+
+   fn add_instance_node(&mut self, prefix: &[NodeIx], node_ix: NodeIx, node: &Node, flow_store: &FlowStore) {
+      let path = join(prefix, node_ix);
+      match node {
+         Node::Element(Element::Prim(pe)) =>
+            self.insert_prim(PrimInstance{path, element: *pe, per_channel: false, processors: vec![mk_prim_element_processor(*pe)]}),
+         Node::Element(Element::PerChannel(pe, channels)) => self.insert_prim(PrimInstance{
+            path, element: *pe, per_channel: true, processors: (0..*channels).map(|_| mk_prim_element_processor(*pe)).collect(),
+         }),
+         Node::Element(Element::Flow(flow_id)) =>
+            flow_store[*flow_id].nodes().for_each(|(node_ix, node)| self.add_instance_node(&path, node_ix, node, flow_store)),
+         Node::Element(Element::Template(flow_id, args)) => {
+            let substitution = Substitution::new(&flow_store[*flow_id], args);
+            flow_store[*flow_id].nodes()
+               .for_each(|(node_ix, node)| self.add_instance_node(&path, node_ix, &substitution.node(node_ix, node), flow_store))
+         }
+         Node::Input{..} | Node::Output{..} => {}
+      }
+   }
+
+   // The substitution of the flow instance at `path`.
+   fn substitution(&self, path: &[NodeIx], flow_store: &FlowStore) -> Substitution {
+      let (mut flow_id, mut substitution) = (self.flow_id, Substitution::identity());
+      for &node_ix in path {
+         substitution = match substitution.node(node_ix, flow_store[flow_id].node(node_ix)) {
+            Node::Element(Element::Flow(sub_flow_id)) => {
+               flow_id = sub_flow_id;
+               Substitution::identity()
+            }
+            Node::Element(Element::Template(sub_flow_id, args)) => {
+               flow_id = sub_flow_id;
+               Substitution::new(&flow_store[sub_flow_id], &args)
+            }
+            _ => unreachable!(),
+         };
+      }
+      substitution
+   }
+
+   pub(super) fn insert_node_processor(&mut self, prefix: &[NodeIx], node_processor: NodeProcessor) {
+      node_processor.prims.into_iter().for_each(|mut prim| {
+         prim.path.splice(0..0, prefix.iter().copied());
+         self.insert_prim(prim);
+      });
+      // Picked up by the next `compile` like the delays it replaces.
+      node_processor.delays.into_iter().for_each(|(path, output_no, ty, buffer)| {
+         let port = (prefix.iter().copied().chain(path).collect(), output_no);
+         self.delays.push(Delay{port, ty, slot: self.slots.len(), source: 0});
+         self.slots.push(AtomicRefCell::new(buffer));
+      });
+   }
+
+   pub(super) fn remove_node(&mut self, prefix: &[NodeIx], node_ix: NodeIx) -> NodeProcessor {
+      let path = join(prefix, node_ix);
+      let start = self.prims.partition_point(|prim| prim.path < path);
+      let end = start + self.prims[start..].iter().take_while(|prim| prim.path.starts_with(&path)).count();
+      let prims = self.prims.drain(start..end).map(|mut prim| { prim.path.drain(..prefix.len()); prim }).collect();
+
+      let slots = &self.slots;
+      let mut delays = Vec::new();
+      self.delays.retain(|delay| {
+         if !delay.port.0.starts_with(&path) { return true }
+         let buffer = std::mem::replace(&mut *slots[delay.slot].borrow_mut(), Buffer::new(delay.ty));
+         delays.push((delay.port.0[prefix.len()..].to_vec(), delay.port.1, delay.ty, buffer));
+         false
+      });
+      NodeProcessor{prims, delays}
+   }
+
+   fn insert_prim(&mut self, prim: PrimInstance) {
+      let ix = self.prims.binary_search_by(|other| other.path.cmp(&prim.path)).unwrap_err();
+      self.prims.insert(ix, prim);
+   }
+
+   pub(super) fn compile(&mut self, flow_store: &FlowStore) {
+      let mut compiler = Compiler{
+         flow_store, prims: &self.prims,
+         slots: Slots{types: Vec::new(), free: Vec::new(), readers: Vec::new()},
+         schedule: Vec::new(), delays: Vec::new(),
+      };
+      compiler.flow(self.flow_id, &Substitution::identity(), &mut Vec::new(), None);
+      let Compiler{slots, schedule, delays, ..} = compiler;
+      let (schedule, delays, optimizations) = optimize(schedule, delays, &slots.types, &self.prims);
+      let (schedule, stages, delays, types) = parallelize(schedule, delays, &slots.types);
+
+      let mut old_slots: Vec<_> = std::mem::take(&mut self.slots).into_iter().map(Some).collect();
+      let mut kept: LinearMap<usize, AtomicRefCell<Buffer>> = delays.iter().filter_map(|delay| {
+         let old = self.delays.iter().find(|old| old.port == delay.port && old.ty == delay.ty)?;
+         old_slots[old.slot].take().map(|buffer| (delay.slot, buffer))
+      }).collect();
+      self.slots = types.into_iter().enumerate().map(|(ix, ty)| {
+         if let Some(buffer) = kept.remove(&ix) { return buffer }
+         if delays.iter().any(|delay| delay.slot == ix) { return AtomicRefCell::new(Buffer::new(ty)) }
+         let reuse = old_slots.iter_mut().find(|slot| matches!(slot, Some(slot) if slot.borrow().test_type(ty)));
+         reuse.and_then(Option::take).unwrap_or_else(|| AtomicRefCell::new(Buffer::new(ty)))
+      }).collect();
+      self.schedule = schedule;
+      self.stages = stages;
+      self.delays = delays;
+      self.optimizations = optimizations;
+      let flow = &flow_store[self.flow_id];
+      self.f_nyq = flow.input_types().map(|(_, ty)| ty).chain(flow.output_types().map(|(_, ty)| ty)).filter_map(rate).max();
    }
 
    fn compute_outplace<BufferRef, BufferRefMut>(
-      &self, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRef>, buffer_sz: usize,
-      flow: &Flow, flow_store: &FlowStore, processor_store: &ProcessorStore
+      &self, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRef>, block: &Block, flow_store: &FlowStore,
+      workers: Option<&rayon::ThreadPool>,
    ) where BufferRef: Deref<Target=Buffer>, BufferRefMut: DerefMut<Target=Buffer>
    {
-      Self::check_buffer_types(input, flow.input_types());
-      Self::check_buffer_types(output, flow.output_types());
-
-      Self::check_buffer_sz(input, buffer_sz);
-      
-      output.iter_mut().for_each(|(_,x)| x.update_size(buffer_sz));
-
-      flow.visit_order().for_each(|&node_ix| {
-         match flow.node(node_ix) {
-            Node::Element(e) => {
-               let in_buffers =
-                  e.input_nos(flow_store).into_iter().filter_map(|input_no| {
-                     let mut edges = flow.input_edges(node_ix, input_no);
-                     if let Some(head) = edges.next() {
-                        let tail_edges = edges;
-                        let mut head_buffer = self.buffer(head).borrow_mut();
-                        tail_edges.for_each(|tail| head_buffer.merge(&self.buffer(tail).borrow()));
-                        Some((input_no, head_buffer))
-                     }
-                     else { None }
-                  }).collect();
-
-               let (mut direct_out_buffers, mut rest_edges): (LinearMap<_,_>, Vec<_>) =
-                  e.output_nos(flow_store).iter().filter_map(|&output_no| {
-                     let mut edges = flow.output_edges(node_ix, output_no);
-                     if let Some(head) = edges.next() {
-                        let mut head_buffer = self.buffer(head).borrow_mut();
-                        head_buffer.update_size(buffer_sz);
-                        Some(((output_no, head_buffer), edges))
-                     }
-                     else { None }
-                  }).unzip();
-               
-               e.compute_outplace(&mut direct_out_buffers, &in_buffers, buffer_sz, flow_store, processor_store);
-
-               rest_edges.iter_mut().zip(&direct_out_buffers).for_each(|(rest_edges, (_,direct_out_buffer))|
-                  rest_edges.for_each(|edge_ix| {
-                     let mut rest_buffer = self.buffer(edge_ix).borrow_mut();
-                     rest_buffer.update_size(buffer_sz);
-                     rest_buffer.clone_from(direct_out_buffer);
-                  })
-               );
-            }
-            Node::Input{no, ..} => {
-               let buffers = flow.output_edges(node_ix, OutputNo(0)).map(|edge_ix| self.buffer(edge_ix).borrow_mut());
-               if let Some(in_buffer) = input.get(no) {
-                  buffers.for_each(|mut buffer| buffer.clone_from(in_buffer));
-               }
-               else {
-                  buffers.for_each(|mut buffer| buffer.clear());
+      let (slots, prims, schedule) = (&self.slots[..], &self.prims[..], &self.schedule[..]);
+      self.delays.iter().for_each(|delay| slots[delay.slot].borrow_mut().update_size(block.size(rate(delay.ty))));
+      self.stages.iter().for_each(|stage| match (&stage[..], workers) {
+         ([_, _, ..], Some(workers)) if work(stage, schedule, prims, block) >= PARALLEL_WORK => workers.install(|| {
+            stage.par_iter().for_each(|task| schedule[task.clone()].iter().for_each(|step| compute_step(step, slots, prims, block, flow_store)))
+         }),
+         _ => stage.iter().flat_map(|task| &schedule[task.clone()]).for_each(|step| match step {
+            Step::Input{no, f_nyq, slot} => {
+               let mut buffer = slots[*slot].borrow_mut();
+               match input.get(no) {
+                  Some(in_buffer) => buffer.copy_from(in_buffer),
+                  None => {
+                     buffer.update_size(block.size(*f_nyq));
+                     buffer.clear();
+                  }
                }
             }
-            Node::Output{no, ..} =>
+            Step::Output{no, f_nyq, sources, default} => {
                if let Some(out_buffer) = output.get_mut(no) {
-                  out_buffer.update_size(buffer_sz);
-
-                  let mut edges = flow.input_edges(node_ix, InputNo(0));
-                  if let Some(head) = edges.next() {
-                     let tail = edges;
-                     out_buffer.clone_from(&self.buffer(head).borrow());
-                     tail.for_each(|edge_ix| out_buffer.merge(&self.buffer(edge_ix).borrow()));
-                  }
-                  else {
-                     out_buffer.clear();
+                  match sources.split_first() {
+                     Some((&head, tail)) => {
+                        out_buffer.copy_from(&slots[head].borrow());
+                        tail.iter().for_each(|&slot| out_buffer.merge(&slots[slot].borrow()));
+                     }
+                     None => {
+                        out_buffer.update_size(block.size(*f_nyq));
+                        match default {
+                           Some(value) => out_buffer.fill(*value),
+                           None => out_buffer.clear(),
+                        }
+                     }
                   }
                }
-         }
+            }
+            step => compute_step(step, slots, prims, block, flow_store),
+         }),
       });
+      self.delays.iter().for_each(|delay| slots[delay.slot].borrow_mut().copy_from(&slots[delay.source].borrow()));
+   }
+
+   pub fn optimizations(&self) -> &Optimizations { &self.optimizations }
+
+   // The reference rate blocks are counted in, and the start of the next block.
+   pub fn f_nyq(&self) -> Option<u64> { self.f_nyq }
+   pub fn clock(&self) -> u64 { self.clock.get() }
+
+   // The elements run per block, in order.
+   pub fn scheduled<'a>(&'a self) -> impl Iterator<Item = &'a [NodeIx]> + 'a {
+      self.schedule.iter().filter_map(move |step| option_match!(step, Step::Prim{prim, ..} => &self.prims[*prim].path[..]))
    }
 
    fn check_buffer_types<T,U,I>(buffer: &LinearMap<T,U>, types: I)
@@ -163,21 +395,525 @@ impl Processor {
       debug_assert!( types.into_iter().all(|(no, ty)| buffer.get(&no).map(|x| x.test_type(ty)).unwrap_or(true)) );
    }
 
-   fn check_buffer_sz<T,U>(buffers: &LinearMap<T,U>, sz: usize) where T: Eq, U: Deref<Target=Buffer> {
-      debug_assert!( buffers.values().filter_map(|x| option_match!(x.deref(), Buffer::Sampled(x) => x)).all(|x| x.len() == sz) );
+   fn check_buffer_sz<T,U,I>(buffers: &LinearMap<T,U>, types: I, block: &Block)
+      where T: Eq, U: Deref<Target=Buffer>, I: IntoIterator<Item = (T, Type)>
+   {
+      debug_assert!( types.into_iter().all(|(no, ty)|
+         match (buffers.get(&no).map(|x| x.deref()), rate(ty)) {
+            (Some(Buffer::Sampled(x)), f_nyq) => x.len() == block.size(f_nyq),
+            (Some(Buffer::Bus(x)), f_nyq) => x.iter().all(|x| matches!(x, Buffer::Sampled(x) if x.len() == block.size(f_nyq))),
+            (Some(Buffer::Event(x)), _) => x.check_times(block.len),
+            _ => true,
+         }
+      ));
    }
+}
 
-   fn buffer(&self, edge_ix: EdgeIx) -> &RefCell<Buffer> {
-      self.buffers.get(edge_ix.index() as u64).unwrap()
+// The number of samples the elements of a stage process in `block`.
+fn work(stage: &[Range<usize>], schedule: &[Step], prims: &[PrimInstance], block: &Block) -> usize {
+   stage.iter().flat_map(|task| &schedule[task.clone()]).map(|step| match step {
+      Step::Prim{prim, f_nyq, ..} => block.size(*f_nyq) * prims[*prim].processors.len(),
+      _ => 0,
+   }).sum()
+}
+
+// Runs a step that only touches slots, which may be done on any thread.
+fn compute_step(step: &Step, slots: &[AtomicRefCell<Buffer>], prims: &[PrimInstance], block: &Block, flow_store: &FlowStore) {
+   match step {
+      Step::Fill{slot, f_nyq, value} => {
+         let mut buffer = slots[*slot].borrow_mut();
+         buffer.update_size(block.size(*f_nyq));
+         match value {
+            Some(value) => buffer.fill(*value),
+            None => buffer.clear(),
+         }
+      }
+      Step::Mix{slot, sources} => {
+         let mut mix = slots[*slot].borrow_mut();
+         mix.copy_from(&slots[sources[0]].borrow());
+         sources[1..].iter().for_each(|&source| mix.merge(&slots[source].borrow()));
+      }
+      Step::Prim{prim, f_nyq, inputs, outputs} => {
+         let buffer_sz = block.size(*f_nyq);
+         outputs.iter().for_each(|&(_, slot)| slots[slot].borrow_mut().update_size(buffer_sz));
+
+         let prim = &prims[*prim];
+         prim.processors.iter().enumerate().for_each(|(channel, processor)| {
+            let channel = Some(channel).filter(|_| prim.per_channel);
+            let mut element_output = Outputs{slots, ports: outputs, channel};
+            let element_input = Inputs{slots, ports: inputs, channel, block: *block, f_nyq: *f_nyq};
+            processor.borrow_mut().compute_outplace(&mut element_output, &element_input, buffer_sz, flow_store);
+         });
+      }
+      Step::Input{..} | Step::Output{..} => unreachable!(),
    }
+}
 
-   pub(super) fn add_edge(&mut self, edge_ix: EdgeIx, ty: Type) {
-      let result = self.buffers.insert(edge_ix.index() as u64, RefCell::new(Buffer::new(ty)));
-      assert!(result);
+// Inlines a flow instance at `path` into the schedule. Nested instances get the slots feeding
+// their inputs, each held by the caller until the instance is compiled, and hand back one
+// slot per output, holding one reader on it for the caller.
+struct Compiler<'a> {
+   flow_store: &'a FlowStore,
+   prims: &'a [PrimInstance],
+   slots: Slots,
+   schedule: Vec<Step>,
+   delays: Vec<Delay>,
+}
+impl Compiler<'_> {
+   fn flow(
+      &mut self, flow_id: FlowId, substitution: &Substitution, path: &mut Vec<NodeIx>, inputs: Option<&LinearMap<InputNo, usize>>,
+   ) -> LinearMap<OutputNo, usize> {
+      let flow_store = self.flow_store;
+      let flow = &flow_store[flow_id];
+      let delays = self.delays.len();
+      flow.feedback_edges().for_each(|(_, source, _, edge)| {
+         let port = (join(path, source), edge.output_no());
+         if self.delays[delays..].iter().all(|delay| delay.port != port) {
+            let ty = substitution.node(source, flow.node(source)).output_types(flow_store).get(&edge.output_no()).copied()
+               .unwrap_or_else(|| edge.ty());
+            self.delays.push(Delay{port, ty, slot: self.slots.reserve(ty), source: 0});
+         }
+      });
+
+      let mut port_slots: LinearMap<(NodeIx, OutputNo), usize> = LinearMap::new();
+      let mut outputs = LinearMap::new();
+      for &node_ix in flow.visit_order() {
+         match &substitution.node(node_ix, flow.node(node_ix)) {
+            Node::Input{no, ty, ..} => {
+               let readers = self.readers(flow, path, delays, node_ix, OutputNo(0));
+               if readers == 0 { continue }
+               let slot = match inputs.map(|inputs| inputs.get(no)) {
+                  Some(Some(&slot)) => {
+                     self.slots.retain(slot, readers);
+                     slot
+                  }
+                  Some(None) => {
+                     let slot = self.slots.alloc(*ty, readers);
+                     self.schedule.push(Step::Fill{slot, f_nyq: rate(*ty), value: None});
+                     slot
+                  }
+                  None => {
+                     let slot = self.slots.alloc(*ty, readers);
+                     self.schedule.push(Step::Input{no: *no, f_nyq: rate(*ty), slot});
+                     slot
+                  }
+               };
+               self.bind(&mut port_slots, path, delays, node_ix, OutputNo(0), slot);
+            }
+            Node::Element(element) => {
+               let mut held = Vec::new();
+               let input_slots: LinearMap<InputNo, usize> = element.input_types(flow_store).into_iter().filter_map(|(input_no, ty)| {
+                  let (live, all) = self.sources(flow, path, &port_slots, delays, node_ix, input_no, ty);
+                  held.extend(live);
+                  let slot = match all.len() {
+                     0 => {
+                        let value = flow.substituted_input_default(node_ix, input_no, substitution, flow_store)?;
+                        let slot = self.slots.alloc(ty, 1);
+                        self.schedule.push(Step::Fill{slot, f_nyq: rate(ty), value: Some(value)});
+                        held.push(slot);
+                        slot
+                     }
+                     1 => all[0],
+                     _ => {
+                        let slot = self.slots.alloc(ty, 1);
+                        self.schedule.push(Step::Mix{slot, sources: all});
+                        held.push(slot);
+                        slot
+                     }
+                  };
+                  Some((input_no, slot))
+               }).collect();
+
+               match element {
+                  Element::Prim(pe) | Element::PerChannel(pe, _) => {
+                     let outputs = element.output_types(flow_store).into_iter().filter_map(|(output_no, ty)| {
+                        let readers = self.readers(flow, path, delays, node_ix, output_no);
+                        if readers == 0 { return None }
+                        let slot = self.slots.alloc(ty, readers);
+                        self.bind(&mut port_slots, path, delays, node_ix, output_no, slot);
+                        Some((output_no, slot))
+                     }).collect();
+                     let prim_path = join(path, node_ix);
+                     let prim = self.prims.binary_search_by(|prim| prim.path.cmp(&prim_path)).unwrap();
+                     let f_nyq = pe.output_types().values().chain(pe.input_types().values()).find_map(|ty| rate(*ty));
+                     self.schedule.push(Step::Prim{prim, f_nyq, inputs: input_slots.into_iter().collect(), outputs});
+                  }
+                  Element::Flow(sub_flow_id) | Element::Template(sub_flow_id, _) => {
+                     let sub_substitution = match element {
+                        Element::Template(_, args) => Substitution::new(&flow_store[*sub_flow_id], args),
+                        _ => Substitution::identity(),
+                     };
+                     path.push(node_ix);
+                     let sub_outputs = self.flow(*sub_flow_id, &sub_substitution, path, Some(&input_slots));
+                     path.pop();
+                     sub_outputs.into_iter().for_each(|(output_no, slot)| {
+                        let readers = self.readers(flow, path, delays, node_ix, output_no);
+                        self.slots.retain(slot, readers);
+                        self.slots.release(slot);
+                        if readers > 0 {
+                           self.bind(&mut port_slots, path, delays, node_ix, output_no, slot);
+                        }
+                     });
+                  }
+               }
+               held.into_iter().for_each(|slot| self.slots.release(slot));
+            }
+            Node::Output{no, ty, ..} => {
+               let (live, all) = self.sources(flow, path, &port_slots, delays, node_ix, InputNo(0), *ty);
+               let default = flow.substituted_input_default(node_ix, InputNo(0), substitution, flow_store);
+               match inputs {
+                  None => self.schedule.push(Step::Output{no: *no, f_nyq: rate(*ty), sources: all, default}),
+                  Some(_) => {
+                     let slot = match all.len() {
+                        1 => {
+                           self.slots.retain(all[0], 1);
+                           all[0]
+                        }
+                        0 => {
+                           let slot = self.slots.alloc(*ty, 1);
+                           self.schedule.push(Step::Fill{slot, f_nyq: rate(*ty), value: default});
+                           slot
+                        }
+                        _ => {
+                           let slot = self.slots.alloc(*ty, 1);
+                           self.schedule.push(Step::Mix{slot, sources: all});
+                           slot
+                        }
+                     };
+                     outputs.insert(*no, slot);
+                  }
+               }
+               live.into_iter().for_each(|slot| self.slots.release(slot));
+            }
+         }
+      }
+      outputs
+   }
+
+   fn readers(&self, flow: &Flow, path: &[NodeIx], delays: usize, node_ix: NodeIx, output_no: OutputNo) -> usize {
+      let port = (join(path, node_ix), output_no);
+      flow.output_edges(node_ix, output_no).count() + self.delays[delays..].iter().any(|delay| delay.port == port) as usize
+   }
+
+   fn bind(&mut self, port_slots: &mut LinearMap<(NodeIx, OutputNo), usize>, path: &[NodeIx], delays: usize, node_ix: NodeIx, output_no: OutputNo, slot: usize) {
+      port_slots.insert((node_ix, output_no), slot);
+      let port = (join(path, node_ix), output_no);
+      if let Some(delay) = self.delays[delays..].iter_mut().find(|delay| delay.port == port) {
+         delay.source = slot;
+      }
+   }
+
+   // The slots read by an input, live ones first. Edges whose type no longer matches the
+   // input, after a nested flow changed, are left out.
+   #[allow(clippy::too_many_arguments)]
+   fn sources(
+      &self, flow: &Flow, path: &[NodeIx], port_slots: &LinearMap<(NodeIx, OutputNo), usize>, delays: usize,
+      node_ix: NodeIx, input_no: InputNo, ty: Type,
+   ) -> (Vec<usize>, Vec<usize>) {
+      let live = flow.input_edges_with_node(node_ix, input_no)
+         .filter_map(|(edge_ix, source)| port_slots.get(&(source, flow.graph()[edge_ix].output_no())).copied())
+         .collect::<Vec<_>>();
+      let delayed = flow.input_feedback_edges_with_node(node_ix, input_no).map(|(_, source, output_no)| {
+         let port = (join(path, source), output_no);
+         self.delays[delays..].iter().find(|delay| delay.port == port).unwrap().slot
+      });
+      let all = live.iter().copied().chain(delayed).filter(|&slot| self.slots.types[slot] == ty).collect();
+      (live, all)
+   }
+}
+
+// Replaces elements whose inputs are all constant by `Fill` steps, then drops the steps and
+// delays that no output depends on. Delays are live once a live step reads them, which may
+// in turn make earlier steps live, so liveness is iterated to a fixed point.
+fn optimize(schedule: Vec<Step>, delays: Vec<Delay>, types: &[Type], prims: &[PrimInstance]) -> (Vec<Step>, Vec<Delay>, Optimizations) {
+   let mut known: Vec<Option<Value>> = vec![None; types.len()];
+   let mut folded = LinearMap::new();
+   let mut steps = Vec::new();
+   for step in schedule {
+      let values = match &step {
+         Step::Prim{prim, inputs, ..} => inputs.iter()
+            .map(|&(input_no, slot)| Some((input_no, known[slot]?)))
+            .collect::<Option<LinearMap<_, _>>>()
+            .and_then(|inputs| prims[*prim].element.fold(&inputs)),
+         _ => None,
+      };
+      match (step, values) {
+         (Step::Prim{prim, outputs, ..}, Some(values)) if !outputs.is_empty() => {
+            outputs.iter().for_each(|&(output_no, slot)| {
+               known[slot] = Some(values[&output_no]);
+               steps.push(Step::Fill{slot, f_nyq: rate(types[slot]), value: known[slot]});
+            });
+            folded.insert(prim, values);
+         }
+         (step, _) => {
+            let value = option_match!(&step, Step::Fill{value, ..} => *value).flatten();
+            step.writes().into_iter().for_each(|slot| known[slot] = value);
+            steps.push(step);
+         }
+      }
+   }
+
+   let mut live_delays = vec![false; delays.len()];
+   let live = loop {
+      let mut needed = vec![false; types.len()];
+      delays.iter().zip(&live_delays).filter(|(_, live)| **live).for_each(|(delay, _)| needed[delay.source] = true);
+      let mut live: Vec<bool> = steps.iter().rev().map(|step| {
+         let writes = step.writes();
+         let live = matches!(step, Step::Output{..}) || writes.iter().any(|&slot| needed[slot]);
+         if live {
+            writes.into_iter().for_each(|slot| needed[slot] = false);
+            step.reads().into_iter().for_each(|slot| needed[slot] = true);
+         }
+         live
+      }).collect();
+      live.reverse();
+
+      let next: Vec<bool> = delays.iter().map(|delay| needed[delay.slot]).collect();
+      if next == live_delays { break live }
+      live_delays = next;
+   };
+
+   let mut run = vec![false; prims.len()];
+   let schedule = steps.into_iter().zip(live).filter(|(_, live)| *live).map(|(step, _)| {
+      if let Step::Prim{prim, ..} = step { run[prim] = true }
+      step
+   }).collect();
+   let mut folded: Vec<_> = folded.into_iter().collect();
+   folded.sort_by_key(|(prim, _)| *prim);
+   let optimizations = Optimizations{
+      pruned: prims.iter().enumerate().filter(|&(prim, _)| !run[prim] && folded.iter().all(|&(folded, _)| folded != prim))
+         .map(|(_, prim)| prim.path.clone()).collect(),
+      folded: folded.into_iter().map(|(prim, values)| (prims[prim].path.clone(), values)).collect(),
+   };
+   let delays = delays.into_iter().zip(live_delays).filter(|(_, live)| *live).map(|(delay, _)| delay).collect();
+   (schedule, delays, optimizations)
+}
+
+// Gives every value written by the schedule a slot of its own, so that only the data flow
+// orders the steps, and groups the steps into stages. A step joins the task of its only
+// predecessor if it is that step's only successor, and otherwise starts a task in the stage
+// after those of its predecessors. Slots are then shared again between values that are not
+// alive at the same time, in different stages or one after the other within a task.
+#[allow(clippy::type_complexity)]
+fn parallelize(schedule: Vec<Step>, mut delays: Vec<Delay>, types: &[Type]) -> (Vec<Step>, Vec<Vec<Range<usize>>>, Vec<Delay>, Vec<Type>) {
+   let mut value_types: Vec<Type> = delays.iter().map(|delay| delay.ty).collect();
+   let mut writers: Vec<Option<usize>> = vec![None; delays.len()];
+   let mut current: Vec<Option<usize>> = vec![None; types.len()];
+   delays.iter().enumerate().for_each(|(value, delay)| current[delay.slot] = Some(value));
+   let mut schedule: Vec<Step> = schedule.into_iter().enumerate().map(|(ix, mut step)| {
+      step.map_slots(|slot, write| {
+         if !write { return current[slot].unwrap() }
+         current[slot] = Some(value_types.len());
+         value_types.push(types[slot]);
+         writers.push(Some(ix));
+         value_types.len() - 1
+      });
+      step
+   }).collect();
+   delays.iter_mut().for_each(|delay| delay.source = current[delay.source].unwrap());
+
+   let preds: Vec<Vec<usize>> = schedule.iter().map(|step| {
+      let mut preds: Vec<usize> = step.reads().into_iter().filter_map(|value| writers[value])
+         .filter(|&pred| !matches!(schedule[pred], Step::Input{..})).collect();
+      preds.sort_unstable();
+      preds.dedup();
+      preds
+   }).collect();
+   let mut successors = vec![0; schedule.len()];
+   preds.iter().zip(&schedule).filter(|(_, step)| !matches!(step, Step::Output{..})).for_each(|(preds, _)|
+      preds.iter().for_each(|&pred| successors[pred] += 1)
+   );
+   let mut task_of = vec![0; schedule.len()];
+   let mut tasks: Vec<(usize, Vec<usize>)> = Vec::new();
+   for (ix, step) in schedule.iter().enumerate() {
+      if matches!(step, Step::Input{..} | Step::Output{..}) { continue }
+      task_of[ix] = match preds[ix][..] {
+         [pred] if successors[pred] == 1 => task_of[pred],
+         _ => {
+            tasks.push((preds[ix].iter().map(|&pred| tasks[task_of[pred]].0 + 1).max().unwrap_or(0), Vec::new()));
+            tasks.len() - 1
+         }
+      };
+      tasks[task_of[ix]].1.push(ix);
+   }
+
+   let mut groups = vec![Vec::new(); tasks.iter().map(|(stage, _)| stage + 1).max().unwrap_or(0)];
+   tasks.into_iter().for_each(|(stage, task)| groups[stage].push(task));
+   let io = |f: fn(&Step) -> bool| vec![(0..schedule.len()).filter(|&ix| f(&schedule[ix])).collect::<Vec<_>>()];
+   let (inputs, outputs) = (io(|step| matches!(step, Step::Input{..})), io(|step| matches!(step, Step::Output{..})));
+   let (mut order, mut places, mut stages) = (Vec::new(), Vec::new(), Vec::new());
+   for group in std::iter::once(inputs).chain(groups).chain(std::iter::once(outputs)) {
+      let stage: Vec<_> = group.into_iter().filter(|task| !task.is_empty()).map(|task| {
+         let start = order.len();
+         places.extend(task.iter().map(|_| (stages.len(), start)));
+         order.extend(task);
+         start..order.len()
+      }).collect();
+      if !stage.is_empty() { stages.push(stage) }
+   }
+
+   // The last access to every value, as its stage, the task if all accesses in that stage
+   // are made by one task, and its position. Delays live on between blocks.
+   let mut last: Vec<(usize, Option<usize>, usize)> = vec![(usize::MAX, None, 0); value_types.len()];
+   order.iter().zip(&places).enumerate().for_each(|(pos, (&ix, &(stage, task)))| {
+      schedule[ix].writes().into_iter().for_each(|value| last[value] = (stage, Some(task), pos));
+      schedule[ix].reads().into_iter().for_each(|value| last[value] = match last[value] {
+         (last_stage, last_task, _) if last_stage == stage => (stage, last_task.filter(|&last_task| last_task == task), pos),
+         _ => (stage, Some(task), pos),
+      });
+   });
+   delays.iter().for_each(|delay| last[delay.source] = (usize::MAX, None, 0));
+
+   let mut slot_types: Vec<Type> = delays.iter().map(|delay| delay.ty).collect();
+   let mut holders: Vec<Option<usize>> = vec![None; delays.len()];
+   let mut slots: Vec<usize> = (0..delays.len()).collect();
+   slots.resize(value_types.len(), 0);
+   order.iter().zip(&places).enumerate().for_each(|(pos, (&ix, &(stage, task)))| schedule[ix].writes().into_iter().for_each(|value| {
+      let ty = value_types[value];
+      let free = |holder: &Option<usize>| holder.is_some_and(|holder| {
+         let (last_stage, last_task, last_pos) = last[holder];
+         last_stage < stage || (last_stage == stage && last_task == Some(task) && last_pos < pos)
+      });
+      slots[value] = match (0..slot_types.len()).find(|&slot| free(&holders[slot]) && same_storage(slot_types[slot], ty)) {
+         Some(slot) => {
+            slot_types[slot] = ty;
+            slot
+         }
+         None => {
+            slot_types.push(ty);
+            holders.push(None);
+            slot_types.len() - 1
+         }
+      };
+      holders[slots[value]] = Some(value).filter(|&value| last[value].0 != usize::MAX);
+   }));
+
+   schedule.iter_mut().for_each(|step| step.map_slots(|value, _| slots[value]));
+   delays.iter_mut().enumerate().for_each(|(value, delay)| {
+      delay.slot = value;
+      delay.source = slots[delay.source];
+   });
+   let mut steps: Vec<Option<Step>> = schedule.into_iter().map(Some).collect();
+   (order.into_iter().map(|ix| steps[ix].take().unwrap()).collect(), stages, delays, slot_types)
+}
+
+impl Step {
+   fn map_slots<F: FnMut(usize, bool) -> usize>(&mut self, mut f: F) {
+      match self {
+         Step::Input{slot, ..} | Step::Fill{slot, ..} => *slot = f(*slot, true),
+         Step::Mix{slot, sources} => {
+            sources.iter_mut().for_each(|source| *source = f(*source, false));
+            *slot = f(*slot, true);
+         }
+         Step::Prim{inputs, outputs, ..} => {
+            inputs.iter_mut().for_each(|(_, slot)| *slot = f(*slot, false));
+            outputs.iter_mut().for_each(|(_, slot)| *slot = f(*slot, true));
+         }
+         Step::Output{sources, ..} => sources.iter_mut().for_each(|source| *source = f(*source, false)),
+      }
    }
 
-   pub(super) fn remove_edge(&mut self, edge_ix: EdgeIx) {
-      self.buffers.remove(edge_ix.index() as u64).unwrap();
+   fn reads(&self) -> Vec<usize> {
+      match self {
+         Step::Input{..} | Step::Fill{..} => Vec::new(),
+         Step::Mix{sources, ..} | Step::Output{sources, ..} => sources.clone(),
+         Step::Prim{inputs, ..} => inputs.iter().map(|&(_, slot)| slot).collect(),
+      }
+   }
+
+   fn writes(&self) -> Vec<usize> {
+      match self {
+         Step::Input{slot, ..} | Step::Fill{slot, ..} | Step::Mix{slot, ..} => vec![*slot],
+         Step::Prim{outputs, ..} => outputs.iter().map(|&(_, slot)| slot).collect(),
+         Step::Output{..} => Vec::new(),
+      }
+   }
+}
+
+// Reserved slots hold a reader that is never released, so they are never reused.
+struct Slots {
+   types: Vec<Type>,
+   free: Vec<usize>,
+   readers: Vec<usize>,
+}
+impl Slots {
+   fn alloc(&mut self, ty: Type, readers: usize) -> usize {
+      let types = &self.types;
+      let slot = match self.free.iter().position(|&slot| same_storage(types[slot], ty)) {
+         Some(ix) => {
+            let slot = self.free.swap_remove(ix);
+            self.types[slot] = ty;
+            slot
+         }
+         None => self.reserve(ty),
+      };
+      self.readers[slot] = readers;
+      slot
+   }
+
+   fn reserve(&mut self, ty: Type) -> usize {
+      self.types.push(ty);
+      self.readers.push(1);
+      self.types.len() - 1
+   }
+
+   fn retain(&mut self, slot: usize, readers: usize) {
+      self.readers[slot] += readers;
+   }
+
+   fn release(&mut self, slot: usize) {
+      self.readers[slot] -= 1;
+      if self.readers[slot] == 0 {
+         self.free.push(slot);
+      }
+   }
+}
+
+fn same_storage(x: Type, y: Type) -> bool {
+   match (x, y) {
+      (Type::Sampled{ty: x, ..}, Type::Sampled{ty: y, ..}) => x == y,
+      (Type::Event(x), Type::Event(y)) => x == y,
+      (Type::Bus{ty: x, channels: x_channels, ..}, Type::Bus{ty: y, channels: y_channels, ..}) => x == y && x_channels == y_channels,
+      _ => false,
+   }
+}
+
+// The ports of one element as it is run. Per-channel elements are run once for every
+// channel and see that channel of each bus, while buffers of other types are shared.
+pub struct Inputs<'a> {
+   slots: &'a [AtomicRefCell<Buffer>],
+   ports: &'a [(InputNo, usize)],
+   channel: Option<usize>,
+   block: Block,
+   f_nyq: Option<u64>,
+}
+impl<'a> Inputs<'a> {
+   pub fn get(&self, no: InputNo) -> Option<AtomicRef<'a, Buffer>> {
+      let channel = self.channel;
+      self.ports.iter().find(|(port, _)| *port == no).map(|&(_, slot)| AtomicRef::map(self.slots[slot].borrow(), |buffer| match (buffer, channel) {
+         (Buffer::Bus(channels), Some(channel)) => &channels[channel],
+         (buffer, _) => buffer,
+      }))
+   }
+
+   // The sample of this element's block at which an event at `time` takes effect.
+   pub fn position(&self, time: u64) -> usize {
+      self.block.position(self.f_nyq, time)
+   }
+}
+
+pub struct Outputs<'a> {
+   slots: &'a [AtomicRefCell<Buffer>],
+   ports: &'a [(OutputNo, usize)],
+   channel: Option<usize>,
+}
+impl<'a> Outputs<'a> {
+   pub fn get_mut(&self, no: OutputNo) -> Option<AtomicRefMut<'a, Buffer>> {
+      let channel = self.channel;
+      self.ports.iter().find(|(port, _)| *port == no).map(|&(_, slot)| AtomicRefMut::map(self.slots[slot].borrow_mut(), |buffer| match (buffer, channel) {
+         (Buffer::Bus(channels), Some(channel)) => &mut channels[channel],
+         (buffer, _) => buffer,
+      }))
    }
 }
 
@@ -186,12 +922,15 @@ impl Processor {
 pub enum Buffer {
    Sampled(GenericSampledBuffer),
    Event(GenericEventBuffer),
+   // One `Buffer::Sampled` per channel.
+   Bus(Vec<Buffer>),
 }
 impl Buffer {
    pub fn new(ty: Type) -> Self {
       match ty {
          Type::Sampled{ty, ..} => Self::Sampled(GenericSampledBuffer::new(ty)),
          Type::Event(ty) => Self::Event(GenericEventBuffer::new(ty)),
+         Type::Bus{channels, ..} => Self::Bus((0..channels).map(|_| Self::new(ty.channel())).collect()),
       }
    }
 
@@ -199,13 +938,25 @@ impl Buffer {
       match (self, ty) {
          (Self::Sampled(buf), Type::Sampled{ty, ..}) if buf.ty() == ty => true,
          (Self::Event(buf), Type::Event(ty)) if buf.ty() == ty => true,
+         (Self::Bus(bufs), Type::Bus{channels, ..}) if bufs.len() == channels as usize => bufs.iter().all(|buf| buf.test_type(ty.channel())),
          _ => false,
       }
    }
 
-   fn update_size(&mut self, sz: usize) {
-      if let Self::Sampled(buf) = self {
-         buf.update_size(sz)
+   pub(super) fn update_size(&mut self, sz: usize) {
+      match self {
+         Self::Sampled(buf) => buf.update_size(sz),
+         Self::Event(_) => {}
+         Self::Bus(bufs) => bufs.iter_mut().for_each(|buf| buf.update_size(sz)),
+      }
+   }
+
+   // Makes room for `samples` samples, so that queues up to that long are kept without allocating.
+   pub(super) fn reserve(&mut self, samples: usize) {
+      match self {
+         Self::Sampled(buf) => buf.reserve(samples),
+         Self::Event(_) => {}
+         Self::Bus(bufs) => bufs.iter_mut().for_each(|buf| buf.reserve(samples)),
       }
    }
 
@@ -213,6 +964,16 @@ impl Buffer {
       match (self, other) {
          (Self::Sampled(x), Self::Sampled(y)) => x.merge(y),
          (Self::Event(x), Self::Event(y)) => x.merge(y),
+         (Self::Bus(x), Self::Bus(y)) => x.iter_mut().zip(y).for_each(|(x, y)| x.merge(y)),
+         _ => unreachable!(),
+      }
+   }
+
+   pub(super) fn copy_from(&mut self, other: &Self) {
+      match (self, other) {
+         (Self::Sampled(x), Self::Sampled(y)) => x.copy_from(y),
+         (Self::Event(x), Self::Event(y)) => x.copy_from(y),
+         (Self::Bus(x), Self::Bus(y)) => x.iter_mut().zip(y).for_each(|(x, y)| x.copy_from(y)),
          _ => unreachable!(),
       }
    }
@@ -221,10 +982,66 @@ impl Buffer {
       match self {
          Self::Sampled(x) => x.clear(),
          Self::Event(x) => x.clear(),
+         Self::Bus(x) => x.iter_mut().for_each(Self::clear),
+      }
+   }
+
+   fn fill(&mut self, value: Value) {
+      match self {
+         Self::Sampled(x) => x.fill(value),
+         Self::Event(_) => unreachable!(),
+         Self::Bus(x) => x.iter_mut().for_each(|x| x.fill(value)),
+      }
+   }
+
+   // Appends the samples of `other`, and its events moved `offset` later.
+   pub(super) fn append(&mut self, other: &Self, offset: u64) {
+      match (self, other) {
+         (Self::Sampled(x), Self::Sampled(y)) => x.append(y),
+         (Self::Event(x), Self::Event(y)) => x.append(y, offset),
+         (Self::Bus(x), Self::Bus(y)) => x.iter_mut().zip(y).for_each(|(x, y)| x.append(y, offset)),
+         _ => unreachable!(),
+      }
+   }
+
+   // Moves the first `samples` samples, or the events before `time`, into `front`. The events
+   // left behind are moved `time` earlier.
+   pub(super) fn take_front(&mut self, front: &mut Self, samples: usize, time: u64) {
+      match (self, front) {
+         (Self::Sampled(x), Self::Sampled(y)) => x.take_front(y, samples),
+         (Self::Event(x), Self::Event(y)) => x.take_front(y, time),
+         (Self::Bus(x), Self::Bus(y)) => x.iter_mut().zip(y).for_each(|(x, y)| x.take_front(y, samples, time)),
+         _ => unreachable!(),
+      }
+   }
+
+   // Bit for bit equality, under which NaNs equal themselves.
+   pub fn identical(&self, other: &Self) -> bool {
+      match (self, other) {
+         (Self::Sampled(x), Self::Sampled(y)) => x.identical(y),
+         (Self::Event(x), Self::Event(y)) => x.identical(y),
+         (Self::Bus(x), Self::Bus(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| x.identical(y)),
+         _ => false,
       }
    }
 }
 
+trait Bits: Copy {
+   fn bits(self) -> u64;
+}
+impl Bits for f32 {
+   fn bits(self) -> u64 { self.to_bits() as u64 }
+}
+impl Bits for num::complex::Complex<f32> {
+   fn bits(self) -> u64 { (self.re.to_bits() as u64) << 32 | self.im.to_bits() as u64 }
+}
+impl Bits for u32 {
+   fn bits(self) -> u64 { self as u64 }
+}
+impl Bits for i32 {
+   fn bits(self) -> u64 { self as u32 as u64 }
+}
+
 #[derive(Clone, Debug)]
 pub enum GenericSampledBuffer {
    F32(SampledBuffer<f32>),
@@ -253,6 +1070,12 @@ macro_rules! impl_generic_sampled_buffer {
             }
          }
 
+         fn reserve(&mut self, samples: usize) {
+            match self {
+               $( Self::$prim_type(buf) => buf.samples.reserve(samples.saturating_sub(buf.len())), )*
+            }
+         }
+
          fn len(&self) -> usize {
             match self {
                $( Self::$prim_type(buf) => buf.len(), )*
@@ -272,12 +1095,44 @@ macro_rules! impl_generic_sampled_buffer {
             }
          }
 
+         fn copy_from(&mut self, other: &Self) {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => x.copy_from(y), )*
+               _ => unreachable!(),
+            }
+         }
+
          pub(super) fn clear(&mut self) {
             match self {
                $( Self::$prim_type(buf) => buf.clear(), )*
             }
          }
 
+         fn append(&mut self, other: &Self) {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => x.samples.extend_from_slice(&y.samples), )*
+               _ => unreachable!(),
+            }
+         }
+
+         fn take_front(&mut self, front: &mut Self, samples: usize) {
+            match (self, front) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => {
+                  y.samples.clear();
+                  y.samples.extend(x.samples.drain(..samples));
+               } )*
+               _ => unreachable!(),
+            }
+         }
+
+         fn identical(&self, other: &Self) -> bool {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) =>
+                  x.len() == y.len() && x.samples.iter().zip(&y.samples).all(|(x, y)| x.bits() == y.bits()), )*
+               _ => false,
+            }
+         }
+
          pub(super) fn fill(&mut self, value: Value) {
             match (self, value) {
                (Self::F32(x), Value::F32(y)) => x.fill(y.into()),
@@ -305,22 +1160,31 @@ impl<T> SampledBuffer<T> {
 
    fn len(&self) -> usize { self.samples.len() }
 
+   // Blocks of streams at other rates than the reference rate are a sample longer every now
+   // and then, so growing buffers make room for that sample too.
    pub(super) fn update_size(&mut self, sz: usize) where T: Default + Clone {
       if self.len() != sz {
+         if sz > self.samples.capacity() {
+            self.samples.reserve_exact(sz + 1 - self.len());
+         }
          self.resize(sz)
       }
    }
 
-   fn merge<'a>(&mut self, other: &'a Self) where T: std::ops::AddAssign<&'a T> {
-      self.samples.iter_mut().zip(&other.samples).for_each(|(x,y)| *x += y);
+   fn merge(&mut self, other: &Self) where T: Copy + std::ops::AddAssign {
+      super::simd::update(&mut self.samples, &other.samples, |mut x, y| { x += y; x });
+   }
+
+   fn copy_from(&mut self, other: &Self) where T: Clone {
+      self.samples.clone_from(&other.samples)
    }
 
-   pub(super) fn clear(&mut self) where T: Default {
-      self.samples.iter_mut().for_each(|x| *x = Default::default());
+   pub(super) fn clear(&mut self) where T: Copy + Default {
+      super::simd::fill(&mut self.samples, Default::default());
    }
 
    fn fill(&mut self, value: T) where T: Copy {
-      self.samples.iter_mut().for_each(|x| *x = value);
+      super::simd::fill(&mut self.samples, value);
    }
 }
 
@@ -354,25 +1218,104 @@ macro_rules! impl_generic_event_buffer {
             }
          }
 
+         fn copy_from(&mut self, other: &Self) {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => x.copy_from(y), )*
+               _ => unreachable!(),
+            }
+         }
+
          pub(super) fn clear(&mut self) {
             match self {
                $( Self::$prim_type(buf) => buf.clear(), )*
             }
          }
+
+         fn append(&mut self, other: &Self, offset: u64) {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => y.events.iter().for_each(|event| x.push(event.time + offset, event.value)), )*
+               _ => unreachable!(),
+            }
+         }
+
+         fn take_front(&mut self, front: &mut Self, time: u64) {
+            match (self, front) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => {
+                  let n = x.events.partition_point(|event| event.time < time);
+                  y.events.clear();
+                  y.events.extend(x.events.drain(..n));
+                  x.events.iter_mut().for_each(|event| event.time -= time);
+               } )*
+               _ => unreachable!(),
+            }
+         }
+
+         fn identical(&self, other: &Self) -> bool {
+            match (self, other) {
+               $( (Self::$prim_type(x), Self::$prim_type(y)) => x.events.len() == y.events.len()
+                  && x.events.iter().zip(&y.events).all(|(x, y)| x.time == y.time && x.value.bits() == y.value.bits()), )*
+               _ => false,
+            }
+         }
+
+         fn check_times(&self, len: usize) -> bool {
+            match self {
+               $( Self::$prim_type(buf) => buf.events.windows(2).all(|pair| pair[0].time <= pair[1].time)
+                  && buf.events.last().map_or(true, |event| event.time < len as u64), )*
+            }
+         }
       }
    }
 }
 enumerate_prim_types!{impl_generic_event_buffer}
 
+// The events a buffer has room for up front, so that blocks with no more events than that
+// are run without allocating.
+const EVENT_CAPACITY: usize = 64;
+
 #[derive(Clone, Debug)]
 pub struct EventBuffer<T> {
-   events: Vec<Event<T>>,
+   pub events: Vec<Event<T>>,
 }
 impl<T> EventBuffer<T> {
-   fn new() -> Self { Self{events: Vec::new()} }
+   fn new() -> Self { Self{events: Vec::with_capacity(EVENT_CAPACITY)} }
 
+   // Events at the same time stay in the order they were pushed, those of `self` first. Both
+   // runs are sorted, so they are merged from the back into the room made at the end.
    fn merge(&mut self, other: &Self) where T: Clone {
-      self.events.extend(other.events.iter().cloned())
+      let mut x = self.events.len();
+      self.events.extend_from_slice(&other.events);
+      for (y, event) in other.events.iter().enumerate().rev() {
+         while x > 0 && self.events[x - 1].time > event.time {
+            self.events[x + y] = self.events[x - 1].clone();
+            x -= 1;
+         }
+         self.events[x + y] = event.clone();
+      }
+   }
+
+   // Events come in time order as a rule, and are only inserted when they do not.
+   pub fn push(&mut self, time: u64, value: T) {
+      match self.events.last() {
+         Some(last) if last.time > time => {
+            let ix = self.events.partition_point(|event| event.time <= time);
+            self.events.insert(ix, Event{time, value});
+         }
+         _ => self.events.push(Event{time, value}),
+      }
+   }
+
+   // Cuts a block of `buffer_sz` samples at the events, yielding the spans of samples between
+   // them along with the events that take effect at the first sample of each. `position` maps
+   // event times to samples, as `Inputs::position` does. Events that fall past the last sample,
+   // as late events do on streams slower than the reference rate, take effect at the last
+   // sample, and the events of a block without samples come as one empty span.
+   pub fn spans<F: Fn(u64) -> usize>(&self, buffer_sz: usize, position: F) -> Spans<'_, T, F> {
+      Spans{events: &self.events, start: 0, buffer_sz, position}
+   }
+
+   fn copy_from(&mut self, other: &Self) where T: Clone {
+      self.events.clone_from(&other.events)
    }
 
    pub(super) fn clear(&mut self) {
@@ -380,8 +1323,316 @@ impl<T> EventBuffer<T> {
    }
 }
 
+// Events are kept in time order. `time` counts samples at the reference rate from the start
+// of the block, the rate `compute_outplace` is given the block size in, so it is less than
+// that size.
 #[derive(Clone, Debug)]
-struct Event<T> {
-   time: u64,
-   value: T,
-}
\ No newline at end of file
+pub struct Event<T> {
+   pub time: u64,
+   pub value: T,
+}
+
+pub struct Spans<'a, T, F> {
+   events: &'a [Event<T>],
+   start: usize,
+   buffer_sz: usize,
+   position: F,
+}
+impl<T, F: Fn(u64) -> usize> Spans<'_, T, F> {
+   fn sample(&self, time: u64) -> usize { (self.position)(time).min(self.buffer_sz.saturating_sub(1)) }
+}
+impl<'a, T, F: Fn(u64) -> usize> Iterator for Spans<'a, T, F> {
+   type Item = (Range<usize>, &'a [Event<T>]);
+
+   fn next(&mut self) -> Option<Self::Item> {
+      if self.start >= self.buffer_sz && self.events.is_empty() { return None }
+      let now = self.events.iter().take_while(|event| self.sample(event.time) <= self.start).count();
+      let (now, rest) = self.events.split_at(now);
+      let end = rest.first().map_or(self.buffer_sz, |event| self.sample(event.time));
+      let span = self.start..end;
+      self.events = rest;
+      self.start = end;
+      Some((span, now))
+   }
+}
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn events(times: &[u64]) -> EventBuffer<u32> {
+      let mut buffer = EventBuffer::new();
+      times.iter().enumerate().for_each(|(value, &time)| buffer.push(time, value as u32));
+      buffer
+   }
+
+   fn spans(buffer: &EventBuffer<u32>, buffer_sz: usize, position: impl Fn(u64) -> usize) -> Vec<(Range<usize>, Vec<u32>)> {
+      buffer.spans(buffer_sz, position).map(|(span, events)| (span, events.iter().map(|event| event.value).collect())).collect()
+   }
+
+   // Renders `blocks` blocks of 16 samples from every output of `flow`, which has no inputs,
+   // one buffer per output.
+   fn render_outputs(store: &super::super::Store, flow: FlowId, blocks: u64) -> Vec<Buffer> {
+      let outputs: Vec<_> = store.flow(flow).unwrap().output_types().collect();
+      let (mut rendered, mut y): (Vec<_>, Vec<_>) = outputs.iter().map(|&(_, ty)| (Buffer::new(ty), Buffer::new(ty))).unzip();
+      for block in 0..blocks {
+         let mut output: LinearMap<OutputNo, &mut Buffer> = outputs.iter().map(|&(no, _)| no).zip(y.iter_mut()).collect();
+         store.compute_outplace(flow, &mut output, &LinearMap::new(), 16);
+         drop(output);
+         rendered.iter_mut().zip(&y).for_each(|(rendered, y)| rendered.append(y, block * 16));
+      }
+      rendered
+   }
+
+   #[test]
+   fn push_keeps_events_sorted_and_stable() {
+      let mut buffer = events(&[5, 1, 5, 0]);
+      buffer.push(1, 4);
+      assert_eq!(buffer.events.iter().map(|event| (event.time, event.value)).collect::<Vec<_>>(), vec![(0, 3), (1, 1), (1, 4), (5, 0), (5, 2)]);
+   }
+
+   #[test]
+   fn merge_interleaves_sorted_events_stably() {
+      let mut buffer = events(&[1, 1, 4, 9]);
+      buffer.merge(&EventBuffer{events: events(&[0, 1, 4, 4, 12]).events.into_iter().map(|event| Event{value: event.value + 10, ..event}).collect()});
+      assert_eq!(buffer.events.iter().map(|event| (event.time, event.value)).collect::<Vec<_>>(),
+         vec![(0, 10), (1, 0), (1, 1), (1, 11), (4, 2), (4, 12), (4, 13), (9, 3), (12, 14)]);
+   }
+
+   #[test]
+   fn spans_cut_at_events() {
+      let buffer = events(&[0, 3, 3, 7]);
+      assert_eq!(spans(&buffer, 10, |time| time as usize), vec![(0..3, vec![0]), (3..7, vec![1, 2]), (7..10, vec![3])]);
+      assert_eq!(spans(&events(&[]), 4, |time| time as usize), vec![(0..4, vec![])]);
+      assert_eq!(spans(&events(&[2]), 4, |time| time as usize), vec![(0..2, vec![]), (2..4, vec![0])]);
+   }
+
+   #[test]
+   fn spans_keep_events_past_the_last_sample() {
+      let block = Block{f_nyq: Some(22050), start: 0, len: 64};
+      assert_eq!(block.size(Some(1000)), 2);
+      assert_eq!(spans(&events(&[10, 63]), 2, |time| block.position(Some(1000), time)), vec![(0..1, vec![0]), (1..2, vec![1])]);
+      assert_eq!(spans(&events(&[1, 2]), 0, |time| time as usize), vec![(0..0, vec![0, 1])]);
+   }
+
+   #[test]
+   fn block_sizes_add_up_without_drift() {
+      let total: usize = (0..100).map(|n| Block{f_nyq: Some(22050), start: n * 37, len: 37}.size(Some(1000))).sum();
+      assert_eq!(total, samples_at(Some(22050), Some(1000), 3700));
+   }
+
+   // Two constants multiplied are folded into one value, and elements no output depends on are
+   // not run. Constants count as folded whether used or not.
+   #[test]
+   fn constants_are_folded_and_dead_elements_pruned() {
+      use super::super::{Store, EdgeMode, prim_element::BinaryOp};
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let binary = |op| Element::Prim(PrimElement::Binary{op, ty: PrimType::F32, f_nyq: 1000});
+      let constant = |x: f32| Element::Prim(PrimElement::Constant{value: Value::F32(x.into()), f_nyq: 1000});
+      let (_, input) = store.add_input(flow, "", ty).unwrap();
+      let (_, output) = store.add_output(flow, "", ty).unwrap();
+      let (two, three) = (store.add_element(flow, constant(2.0)).unwrap(), store.add_element(flow, constant(3.0)).unwrap());
+      let multiply = store.add_element(flow, binary(BinaryOp::Multiply)).unwrap();
+      let add = store.add_element(flow, binary(BinaryOp::Add)).unwrap();
+      let osc = store.add_element(flow, Element::Prim(PrimElement::SineOscF32{f_nyq: 1000})).unwrap();
+      let amp = store.add_element(flow, constant(440.0)).unwrap();
+      store.add_edge(flow, two, OutputNo(0), multiply, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, three, OutputNo(0), multiply, InputNo(1), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, multiply, OutputNo(0), add, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, input, OutputNo(0), add, InputNo(1), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, add, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(flow, amp, OutputNo(0), osc, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let processor = store.processor_store().processor(flow);
+      let optimizations = processor.optimizations();
+      assert_eq!(optimizations.pruned, vec![vec![osc]]);
+      assert_eq!(optimizations.folded.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>(), vec![vec![two], vec![three], vec![multiply], vec![amp]]);
+      assert_eq!(optimizations.folded[2].1, linear_map!{OutputNo(0) => Value::F32(6.0.into())});
+      assert_eq!(processor.scheduled().collect::<Vec<_>>(), vec![&[add][..]]);
+
+      let mut x = Buffer::new(ty);
+      unwrap_match!(&mut x, Buffer::Sampled(GenericSampledBuffer::F32(x)) => x.samples = vec![0.0, 1.0, 2.0, 3.0]);
+      let mut y = Buffer::new(ty);
+      let input: LinearMap<InputNo, &mut Buffer> = std::iter::once((InputNo(0), &mut x)).collect();
+      let mut output: LinearMap<OutputNo, &mut Buffer> = std::iter::once((OutputNo(0), &mut y)).collect();
+      store.compute_outplace(flow, &mut output, &input, 4);
+      drop(output);
+      assert_eq!(unwrap_match!(y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y.samples), vec![6.0, 7.0, 8.0, 9.0]);
+   }
+
+   // Each converter once fed a constant default, which is folded if it can be, and once the
+   // same value as a stream.
+   #[test]
+   fn folded_converters_render_as_they_would_run() {
+      use super::super::{Store, EdgeMode};
+      let converters = [
+         (PrimElement::Resample{ty: PrimType::F32, from_f_nyq: 1000, to_f_nyq: 2000}, Value::F32(0.5.into()), false),
+         (PrimElement::Upmix{ty: PrimType::F32, f_nyq: 1000, channels: 2}, Value::F32(0.1.into()), true),
+         (PrimElement::Downmix{ty: PrimType::F32, f_nyq: 1000, channels: 3}, Value::F32(0.1.into()), true),
+         (PrimElement::Downmix{ty: PrimType::I32, f_nyq: 1000, channels: 3}, Value::I32(-7), true),
+      ];
+      for &(converter, x, foldable) in &converters {
+         let render = |folded: bool| {
+            let mut store = Store::new();
+            let flow = store.add_flow();
+            let (in_ty, out_ty) = (converter.input_types()[&InputNo(0)], converter.output_types()[&OutputNo(0)]);
+            let (_, input) = store.add_input(flow, "", in_ty).unwrap();
+            let (_, output) = store.add_output(flow, "", out_ty).unwrap();
+            let node = store.add_element(flow, Element::Prim(converter)).unwrap();
+            store.add_edge(flow, node, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+            match folded {
+               true => { store.set_default(flow, node, InputNo(0), Some(x)).unwrap(); }
+               false => { store.add_edge(flow, input, OutputNo(0), node, InputNo(0), EdgeMode::Exact).unwrap(); }
+            }
+            let processor = store.processor_store().processor(flow);
+            assert_eq!(!processor.optimizations().folded.is_empty(), folded && foldable, "{:?}", converter);
+
+            let mut x_buffer = Buffer::new(in_ty);
+            x_buffer.update_size(samples_at(processor.f_nyq(), rate(in_ty), 8));
+            x_buffer.fill(x);
+            let mut y = Buffer::new(out_ty);
+            let input: LinearMap<InputNo, &mut Buffer> = std::iter::once((InputNo(0), &mut x_buffer)).collect();
+            let mut output: LinearMap<OutputNo, &mut Buffer> = std::iter::once((OutputNo(0), &mut y)).collect();
+            let mut rendered = Buffer::new(out_ty);
+            for block in 0..3 {
+               store.compute_outplace(flow, &mut output, &input, 8);
+               rendered.append(output[&OutputNo(0)], block * 8);
+            }
+            rendered
+         };
+         assert!(render(true).identical(&render(false)), "{:?}", converter);
+      }
+   }
+
+   // An adder fed back into itself counts blocks: the feedback edge delivers the previous
+   // block's output, and silence before the first.
+   #[test]
+   fn feedback_edges_delay_by_one_block() {
+      use super::super::{Store, EdgeMode, flow::EdgeError, prim_element::BinaryOp};
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let add = store.add_element(flow, Element::Prim(PrimElement::Binary{op: BinaryOp::Add, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(flow, "", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      store.add_edge(flow, add, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      store.set_default(flow, add, InputNo(1), Some(Value::F32(1.0.into()))).unwrap();
+      assert_eq!(store.add_edge(flow, add, OutputNo(0), add, InputNo(0), EdgeMode::Exact), Err(EdgeError::WouldCycle));
+      store.add_feedback_edge(flow, add, OutputNo(0), add, InputNo(0)).unwrap();
+
+      let mut y = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+      for block in 1..=3 {
+         let mut output: LinearMap<OutputNo, &mut Buffer> = std::iter::once((OutputNo(0), &mut y)).collect();
+         store.compute_outplace(flow, &mut output, &LinearMap::new(), 4);
+         assert_eq!(unwrap_match!(&y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => &y.samples[..]), &[block as f32; 4]);
+      }
+   }
+
+   // Equal oscillators in one flow each keep their own phase, and sound as one alone would.
+   #[test]
+   fn equal_elements_keep_their_own_state() {
+      use super::super::{Store, EdgeMode};
+      let mut store = Store::new();
+      let oscs = |store: &mut Store, n| {
+         let flow = store.add_flow();
+         for _ in 0..n {
+            let osc = store.add_element(flow, Element::Prim(PrimElement::SineOscF32{f_nyq: 1000})).unwrap();
+            let (_, output) = store.add_output(flow, "", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+            store.set_default(flow, osc, InputNo(0), Some(Value::F32(110.0.into()))).unwrap();
+            store.add_edge(flow, osc, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+         }
+         flow
+      };
+      let (one, two) = (oscs(&mut store, 1), oscs(&mut store, 2));
+      let (alone, together) = (render_outputs(&store, one, 3), render_outputs(&store, two, 3));
+      assert!(together.iter().all(|y| y.identical(&alone[0])));
+   }
+
+   // Two uses of a flow with an oscillator each sound as the flow alone, which keeps a phase
+   // of its own as well.
+   #[test]
+   fn uses_of_a_flow_keep_their_own_state() {
+      use super::super::{Store, EdgeMode};
+      let mut store = Store::new();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let inner = store.add_flow();
+      let osc = store.add_element(inner, Element::Prim(PrimElement::SineOscF32{f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(inner, "", ty).unwrap();
+      store.set_default(inner, osc, InputNo(0), Some(Value::F32(110.0.into()))).unwrap();
+      store.add_edge(inner, osc, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      let outer = store.add_flow();
+      for _ in 0..2 {
+         let node = store.add_element(outer, Element::Flow(inner)).unwrap();
+         let (_, output) = store.add_output(outer, "", ty).unwrap();
+         store.add_edge(outer, node, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      }
+
+      let used = render_outputs(&store, outer, 3);
+      let alone = render_outputs(&store, inner, 3);
+      assert!(used.iter().all(|y| y.identical(&alone[0])));
+   }
+
+   // An oscillator two flows deep is scheduled by its path from the outer flow, in one
+   // schedule with the gain of the flow in between, whose factor defaults to one.
+   #[test]
+   fn nested_flows_are_scheduled_flat() {
+      use super::super::{Store, EdgeMode, prim_element::BinaryOp};
+      let mut store = Store::new();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let inner = store.add_flow();
+      let osc = store.add_element(inner, Element::Prim(PrimElement::SineOscF32{f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(inner, "", ty).unwrap();
+      store.set_default(inner, osc, InputNo(0), Some(Value::F32(110.0.into()))).unwrap();
+      store.add_edge(inner, osc, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let middle = store.add_flow();
+      let node = store.add_element(middle, Element::Flow(inner)).unwrap();
+      let gain = store.add_element(middle, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(middle, "", ty).unwrap();
+      store.add_edge(middle, node, OutputNo(0), gain, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(middle, gain, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let outer = store.add_flow();
+      let middle_node = store.add_element(outer, Element::Flow(middle)).unwrap();
+      let (_, output) = store.add_output(outer, "", ty).unwrap();
+      store.add_edge(outer, middle_node, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+
+      let processor = store.processor_store().processor(outer);
+      assert_eq!(processor.scheduled().collect::<Vec<_>>(), vec![&[middle_node, node, osc][..], &[middle_node, gain][..]]);
+      assert!(processor.optimizations().pruned.is_empty() && processor.optimizations().folded.is_empty());
+      let (nested, flat) = (render_outputs(&store, outer, 3), render_outputs(&store, inner, 3));
+      assert!(nested[0].identical(&flat[0]));
+   }
+
+   // Branches of oscillators at different frequencies, each large enough to be run on workers.
+   #[test]
+   fn parallel_stages_render_the_same() {
+      use super::super::{Store, EdgeMode, prim_element::BinaryOp};
+      let render = |workers: Option<&rayon::ThreadPool>| {
+         let mut store = Store::new();
+         let flow = store.add_flow();
+         let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 22050};
+         for freq in &[110.0, 220.0, 330.0, 440.0] {
+            let osc = store.add_element(flow, Element::Prim(PrimElement::SineOscF32{f_nyq: 22050})).unwrap();
+            let gain = store.add_element(flow, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 22050})).unwrap();
+            let (_, output) = store.add_output(flow, "", ty).unwrap();
+            store.set_default(flow, osc, InputNo(0), Some(Value::F32((*freq as f32).into()))).unwrap();
+            store.set_default(flow, gain, InputNo(1), Some(Value::F32(0.5.into()))).unwrap();
+            store.add_edge(flow, osc, OutputNo(0), gain, InputNo(0), EdgeMode::Exact).unwrap();
+            store.add_edge(flow, gain, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+         }
+         let mut buffers: Vec<_> = (0..4).map(|_| Buffer::new(ty)).collect();
+         let mut output: LinearMap<OutputNo, &mut Buffer> = buffers.iter_mut().enumerate().map(|(no, buffer)| (OutputNo(no as u32), buffer)).collect();
+         for _ in 0..2 {
+            match workers {
+               Some(workers) => store.compute_outplace_parallel(workers, flow, &mut output, &LinearMap::new(), 4096),
+               None => store.compute_outplace(flow, &mut output, &LinearMap::new(), 4096),
+            }
+         }
+         drop(output);
+         buffers
+      };
+      let workers = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+      let (sequential, parallel) = (render(None), render(Some(&workers)));
+      assert!(sequential.iter().zip(&parallel).all(|(sequential, parallel)| sequential.identical(parallel)));
+   }
+}
@@ -0,0 +1,139 @@
+// Element-wise loops over sample slices. Each loop is compiled twice, once for the baseline
+// instruction set of the target and once for AVX2, and the AVX2 build is picked at run time on
+// processors that have it. The loops leave the vectorizing to the compiler, which needs the
+// operation to be the same for the whole slice: callers pass a closure doing one operation
+// rather than one that picks an operation per sample. Both builds do the same operations on
+// the same samples, so they give the same results to the bit, but for which NaN comes out of
+// an operation on two NaNs.
+
+#[cfg(target_arch = "x86_64")]
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// The build the kernels run, detected on the first call: 0 until then, 1 for the portable
+// builds and 2 for the AVX2 ones. The kernels are generic over their closures, so there is no
+// pointer to keep per kernel, and the one pick holds for all of them.
+#[cfg(target_arch = "x86_64")]
+static BUILD: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(target_arch = "x86_64")]
+fn avx2_detected() -> bool {
+   match BUILD.load(Ordering::Relaxed) {
+      0 => {
+         let avx2 = is_x86_feature_detected!("avx2");
+         BUILD.store(1 + avx2 as u8, Ordering::Relaxed);
+         avx2
+      }
+      build => build == 2,
+   }
+}
+
+macro_rules! kernels {
+   ($(fn $name:ident<$($param:ident: $bound:path),*>($($arg:ident: $ty:ty),*) $body:block)*) => {
+      mod portable {
+         $(
+            #[inline(always)]
+            pub(super) fn $name<$($param: $bound),*>($($arg: $ty),*) $body
+         )*
+      }
+
+      #[cfg(target_arch = "x86_64")]
+      mod avx2 {
+         $(
+            #[target_feature(enable = "avx2")]
+            pub(super) unsafe fn $name<$($param: $bound),*>($($arg: $ty),*) { super::portable::$name($($arg),*) }
+         )*
+      }
+
+      $(
+         pub(super) fn $name<$($param: $bound),*>($($arg: $ty),*) {
+            #[cfg(target_arch = "x86_64")]
+            if avx2_detected() {
+               return unsafe { avx2::$name($($arg),*) };
+            }
+            portable::$name($($arg),*)
+         }
+      )*
+   }
+}
+
+// Slices are cut to the shortest one first, which spares the bounds checks in the loops.
+kernels!{
+   fn fill<T: Copy>(y: &mut [T], value: T) {
+      y.iter_mut().for_each(|y| *y = value);
+   }
+
+   fn map<T: Copy, F: Fn(T) -> T>(y: &mut [T], x: &[T], f: F) {
+      let n = y.len().min(x.len());
+      let (y, x) = (&mut y[..n], &x[..n]);
+      (0..n).for_each(|t| y[t] = f(x[t]));
+   }
+
+   fn update<T: Copy, F: Fn(T, T) -> T>(y: &mut [T], x: &[T], f: F) {
+      let n = y.len().min(x.len());
+      let (y, x) = (&mut y[..n], &x[..n]);
+      (0..n).for_each(|t| y[t] = f(y[t], x[t]));
+   }
+
+   fn zip<T: Copy, F: Fn(T, T) -> T>(y: &mut [T], x0: &[T], x1: &[T], f: F) {
+      let n = y.len().min(x0.len()).min(x1.len());
+      let (y, x0, x1) = (&mut y[..n], &x0[..n], &x1[..n]);
+      (0..n).for_each(|t| y[t] = f(x0[t], x1[t]));
+   }
+
+   fn zip3<T: Copy, F: Fn(T, T, T) -> T>(y: &mut [T], x0: &[T], x1: &[T], x2: &[T], f: F) {
+      let n = y.len().min(x0.len()).min(x1.len()).min(x2.len());
+      let (y, x0, x1, x2) = (&mut y[..n], &x0[..n], &x1[..n], &x2[..n]);
+      (0..n).for_each(|t| y[t] = f(x0[t], x1[t], x2[t]));
+   }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+   use super::*;
+   use num::complex::Complex;
+
+   // Runs `kernel` with both builds on every length up to a few vectors and on slices starting
+   // off the alignment of the vectors, and compares the results bit by bit.
+   fn same_bits<T: Copy, B: PartialEq + std::fmt::Debug>(
+      x: &[T], bits: impl Fn(T) -> B, kernel: impl Fn(bool, &mut [T], &[T], &[T], &[T]),
+   ) {
+      for offset in 0..4 {
+         for n in 0..=(x.len() - offset - 2) {
+            let (x0, x1, x2) = (&x[offset..offset + n], &x[offset + 1..offset + n + 1], &x[offset + 2..offset + n + 2]);
+            let (mut portable, mut avx2) = (x0.to_vec(), x0.to_vec());
+            kernel(false, &mut portable[..], x0, x1, x2);
+            kernel(true, &mut avx2[..], x0, x1, x2);
+            assert_eq!(portable.into_iter().map(&bits).collect::<Vec<_>>(), avx2.into_iter().map(&bits).collect::<Vec<_>>(), "{} {}", offset, n);
+         }
+      }
+   }
+
+   #[test]
+   fn avx2_builds_give_the_portable_results() {
+      if !is_x86_feature_detected!("avx2") {
+         return
+      }
+      let f: Vec<f32> = (0..71).map(|t| ((t * 37 % 23) as f32 - 11.0) * 0.37).chain(vec![f32::INFINITY, -0.0, 1e-40, f32::NAN]).collect();
+      let i: Vec<i32> = (0..75).map(|t: i32| (t - 37).wrapping_mul(0x3b9a_ca07)).collect();
+      let c: Vec<Complex<f32>> = f.iter().zip(f.iter().rev()).map(|(&re, &im)| Complex::new(re, im)).collect();
+
+      macro_rules! run {
+         ($avx2:expr, $name:ident($($arg:expr),*)) => {
+            if $avx2 { unsafe { avx2::$name($($arg),*) } } else { portable::$name($($arg),*) }
+         }
+      }
+      macro_rules! check {
+         ($x:expr, $bits:expr, $map:expr, $update:expr, $zip:expr, $zip3:expr) => {
+            let value = $x[0];
+            same_bits($x, $bits, |avx2, y, _, _, _| run!(avx2, fill(y, value)));
+            same_bits($x, $bits, |avx2, y, x0, _, _| run!(avx2, map(y, x0, $map)));
+            same_bits($x, $bits, |avx2, y, _, x1, _| run!(avx2, update(y, x1, $update)));
+            same_bits($x, $bits, |avx2, y, x0, _, x2| run!(avx2, zip(y, x0, x2, $zip)));
+            same_bits($x, $bits, |avx2, y, x0, x1, x2| run!(avx2, zip3(y, x0, x1, x2, $zip3)));
+         }
+      }
+      check!(&f, f32::to_bits, |x| x * 0.5 + 1.0, |y, x| y * x - y, |x0, x1| x0 / x1, |x0, x1, x2| x0.max(x1).min(x2));
+      check!(&i, |x| x, |x| x.wrapping_mul(3) >> 1, |y, x| y.wrapping_add(x), |x0, x1| x0.checked_div(x1).unwrap_or(0), |x0, x1, x2| x0.max(x1).min(x2));
+      check!(&c, |x: Complex<f32>| (x.re.to_bits(), x.im.to_bits()), |x| x * 0.5, |y, x| y * x, |x0, x1| x0 - x1, |x0, x1, x2| x0 * x1 + x2);
+   }
+}
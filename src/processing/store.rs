@@ -1,85 +1,693 @@
 use super::{
-   flow_store::{FlowStore, FlowId},
-   flow::{self, Flow, NodeIx, EdgeIx, EdgeError},
-   processor::{ProcessorStore, Buffer, Processor},
+   flow_store::{FlowStore, FlowId, FlowError},
+   flow::{self, NodeIx, EdgeIx, FeedbackIx, EdgeError, DefaultError},
+   processor::{ProcessorStore, Processor, Buffer},
+   history::{History, Edit},
    element,
-   Type, OutputNo, InputNo,
+   template::Param,
+   Type, Value, OutputNo, InputNo,
 };
 
 use daggy::stable_dag::Walker;
 use linear_map::LinearMap;
 use std::ops::DerefMut;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeMode {
+   Exact,
+   Convert,
+}
+
 pub struct Store {
    flows: FlowStore,
    processors: ProcessorStore,
+   history: History,
 }
 
 impl Store {
    pub fn new() -> Store {
-      Self{flows: FlowStore::new(), processors: ProcessorStore::new()}
+      Self{flows: FlowStore::new(), processors: ProcessorStore::new(), history: History::new()}
    }
 
    pub fn flow_store(&self) -> &FlowStore { &self.flows }
    pub fn processor_store(&self) -> &ProcessorStore { &self.processors }
 
+   pub fn flow(&self, flow_id: FlowId) -> Option<&flow::Flow> { self.flows.get(flow_id) }
+
+   pub fn flows<'a>(&'a self) -> impl Iterator<Item = (FlowId, &'a flow::Flow)> + 'a { self.flows.iter() }
+
+   pub fn flows_referencing<'a>(&'a self, flow_id: FlowId) -> impl Iterator<Item = FlowId> + 'a { self.flows.referencing(flow_id) }
+
    pub fn add_flow(&mut self) -> FlowId {
-      let flow_id = self.flows.add();
-      self.processors.add(flow_id);
+      let inverse = self.apply(Edit::AddFlow(None));
+      let flow_id = match inverse { Edit::RemoveFlow(flow_id) => flow_id, _ => unreachable!() };
+      self.history.record(inverse);
       flow_id
    }
 
-   pub fn remove_flow(&mut self, flow_id: FlowId) -> (Flow, Processor) {
-      (self.flows.remove(flow_id), self.processors.remove(flow_id))
+   // Refuses to remove a flow that other flows still use.
+   pub fn remove_flow(&mut self, flow_id: FlowId) -> Result<(), FlowError> {
+      self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      let users: Vec<_> = self.flows.referencing(flow_id).collect();
+      if !users.is_empty() {
+         return Err(FlowError::InUse{flow_id, users})
+      }
+
+      let inverse = self.apply(Edit::RemoveFlow(flow_id));
+      self.history.record(inverse);
+      Ok(())
+   }
+
+   // Removes a flow along with the nodes of other flows that use it.
+   pub fn remove_flow_cascade(&mut self, flow_id: FlowId) -> Result<(), FlowError> {
+      self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      let nodes: Vec<_> = self.flows.referencing(flow_id).flat_map(|user| {
+         self.flows[user].nodes()
+            .filter(|(_, node)| matches!(node, flow::Node::Element(element) if element.flow_id() == Some(flow_id)))
+            .map(move |(node_ix, _)| (user, node_ix))
+      }).collect();
+
+      self.transaction(|store| {
+         nodes.into_iter().for_each(|(user, node_ix)| { store.remove_node(user, node_ix).unwrap(); });
+         store.remove_flow(flow_id)
+      })
    }
 
-   pub fn add_element(&mut self, flow_id: FlowId, element: element::Element) -> NodeIx {
-      if let element::Element::Prim(pe_id) = element {
-         self.processors.use_prim_element_processor(pe_id);
+   pub fn add_element(&mut self, flow_id: FlowId, element: element::Element) -> Result<NodeIx, FlowError> {
+      match element {
+         element::Element::Flow(sub_flow_id) => self.flows.check_reference(flow_id, sub_flow_id)?,
+         element::Element::Template(sub_flow_id, ref args) => {
+            self.flows.check_reference(flow_id, sub_flow_id)?;
+            self.flows.check_instance(sub_flow_id, args)?;
+         }
+         element::Element::Prim(_) | element::Element::PerChannel(..) => { self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?; }
       }
-      self.flows[flow_id].add_element(element)
+      Ok(self.add_node(flow_id, flow::Node::Element(element)))
    }
 
-   pub fn add_input(&mut self, flow_id: FlowId, ty: Type) -> (InputNo, NodeIx) {
-      self.flows[flow_id].add_input(ty)
+   // Declares the template parameters of a flow. Refused while the flow is in use, since the
+   // arguments of its instances would no longer fit.
+   pub fn set_params(&mut self, flow_id: FlowId, params: Vec<Param>) -> Result<Vec<Param>, FlowError> {
+      self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?;
+      let users: Vec<_> = self.flows.referencing(flow_id).collect();
+      if !users.is_empty() {
+         return Err(FlowError::InUse{flow_id, users})
+      }
+      self.insert_params(flow_id, params)
    }
 
-   pub fn add_output(&mut self, flow_id: FlowId, ty: Type) -> (OutputNo, NodeIx) {
-      self.flows[flow_id].add_output(ty)
+   // Sets the parameters of a flow regardless of its users, for loading patches whose
+   // instances are checked once the flow is complete.
+   pub(super) fn insert_params(&mut self, flow_id: FlowId, params: Vec<Param>) -> Result<Vec<Param>, FlowError> {
+      self.flows.check_params(flow_id, &params)?;
+      let inverse = self.apply(Edit::SetParams{flow_id, params});
+      let old = match &inverse { Edit::SetParams{params, ..} => params.clone(), _ => unreachable!() };
+      self.history.record(inverse);
+      Ok(old)
    }
 
-   pub fn remove_node(&mut self, flow_id: FlowId, node_ix: NodeIx) -> Option<flow::Node> {
-      let processors = &mut self.processors;
-      let flow = &mut self.flows[flow_id];
-      let graph = flow.graph();
-      graph.parents(node_ix).iter(graph).chain(graph.children(node_ix).iter(graph))
-         .for_each(|(edge_ix, _)| processors.processor_mut(flow_id).remove_edge(edge_ix));
+   pub fn add_input(&mut self, flow_id: FlowId, name: &str, ty: Type) -> Result<(InputNo, NodeIx), FlowError> {
+      let no = self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?.next_input_no();
+      Ok((no, self.add_node(flow_id, flow::Node::Input{no, ty, name: name.to_string()})))
+   }
+
+   pub fn add_output(&mut self, flow_id: FlowId, name: &str, ty: Type) -> Result<(OutputNo, NodeIx), FlowError> {
+      let no = self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?.next_output_no();
+      Ok((no, self.add_node(flow_id, flow::Node::Output{no, ty, name: name.to_string()})))
+   }
+
+   fn add_node(&mut self, flow_id: FlowId, node: flow::Node) -> NodeIx {
+      let inverse = self.apply(Edit::AddNode{flow_id, node, node_processors: None});
+      let node_ix = match inverse { Edit::RemoveNode{node_ix, ..} => node_ix, _ => unreachable!() };
+      self.history.record(inverse);
+      node_ix
+   }
 
-      flow.remove_node(node_ix).map(|node| {
-         if let flow::Node::Element(element::Element::Prim(pe_id)) = node {
-            self.processors.free_prim_element_processor(pe_id);
+   pub fn remove_node(&mut self, flow_id: FlowId, node_ix: NodeIx) -> Result<flow::Node, FlowError> {
+      let graph = self.flows.get(flow_id).ok_or(FlowError::UnknownFlow(flow_id))?.graph();
+      graph.node_weight(node_ix).ok_or(FlowError::UnknownNode{flow_id, node_ix})?;
+      let edges: Vec<_> = graph.parents(node_ix).iter(graph).chain(graph.children(node_ix).iter(graph))
+         .map(|(edge_ix, _)| edge_ix)
+         .collect();
+      let feedback_edges: Vec<_> = self.flows[flow_id].node_feedback_edges(node_ix).collect();
+
+      let converter = self.flows[flow_id].is_converter(node_ix);
+      let defaults: Vec<_> = self.flows[flow_id].node_defaults(node_ix).map(|(input_no, _)| input_no).collect();
+      let params = self.flows[flow_id].params();
+      let unbound = params.iter().any(|param| param.bindings.iter().any(|binding| binding.node_ix() == node_ix)).then(|| {
+         params.iter().map(|param| Param{bindings: param.bindings.iter().copied().filter(|binding| binding.node_ix() != node_ix).collect(), ..param.clone()})
+            .collect::<Vec<_>>()
+      });
+
+      self.transaction(|store| {
+         if converter {
+            store.set_converter(flow_id, node_ix, false);
+         }
+         defaults.into_iter().for_each(|input_no| { store.set_default(flow_id, node_ix, input_no, None).unwrap(); });
+         if let Some(params) = unbound {
+            let inverse = store.apply(Edit::SetParams{flow_id, params});
+            store.history.record(inverse);
          }
-         node
+         edges.into_iter().for_each(|edge_ix| { store.remove_edge(flow_id, edge_ix); });
+         feedback_edges.into_iter().for_each(|feedback_ix| { store.remove_feedback_edge(flow_id, feedback_ix); });
+
+         let inverse = store.apply(Edit::RemoveNode{flow_id, node_ix});
+         let node = match &inverse { Edit::AddNode{node, ..} => node.clone(), _ => unreachable!() };
+         store.history.record(inverse);
+         Ok(node)
       })
    }
 
-   pub fn add_edge(&mut self, flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo)
+   // An unconnected type-generic element takes on the type of its first input edge.
+   // With `EdgeMode::Convert`, mismatched types are joined through a chain of converter
+   // elements, and the returned edge is the one that ends at `target`.
+   pub fn add_edge(&mut self, flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo, mode: EdgeMode)
       -> Result<(EdgeIx, Type), EdgeError>
    {
-      let (edge_ix, ty) = self.flows.alter(flow_id, |flow_store, flow| flow.add_edge(source, output_no, target, input_no, flow_store))?;
-      self.processors.processor_mut(flow_id).add_edge(edge_ix, ty);
-      Ok((edge_ix, ty))
+      let flow = self.flows.get(flow_id).ok_or(EdgeError::UnknownFlow(flow_id))?;
+      if let Some(element) = flow.inferred_element(source, output_no, target, input_no, &self.flows) {
+         if flow.would_cycle(source, target) {
+            return Err(EdgeError::WouldCycle)
+         }
+         return self.try_transaction(|store| {
+            let inverse = store.apply(Edit::SetElement{flow_id, node_ix: target, element});
+            store.history.record(inverse);
+            store.add_edge(flow_id, source, output_no, target, input_no, mode)
+         })
+      }
+      let converters = match (mode, flow.edge_type(source, output_no, target, input_no, &self.flows)) {
+         (EdgeMode::Convert, Err(EdgeError::TypeMismatch{expected, actual})) =>
+            element::Element::converters(actual, expected).ok_or(EdgeError::TypeMismatch{expected, actual})?,
+         (_, result) => { result?; Vec::new() }
+      };
+      if converters.is_empty() {
+         let (edge_ix, ty) = self.insert_edge(flow_id, source, output_no, target, input_no)?;
+         self.history.record(Edit::RemoveEdge{flow_id, edge_ix});
+         return Ok((edge_ix, ty))
+      }
+      if flow.would_cycle(source, target) {
+         return Err(EdgeError::WouldCycle)
+      }
+
+      self.try_transaction(|store| {
+         let (source, output_no) = converters.into_iter().fold((source, output_no), |(source, output_no), converter| {
+            let node_ix = store.add_element(flow_id, converter).unwrap();
+            store.set_converter(flow_id, node_ix, true);
+            store.add_edge(flow_id, source, output_no, node_ix, InputNo(0), EdgeMode::Exact).unwrap();
+            (node_ix, OutputNo(0))
+         });
+         store.add_edge(flow_id, source, output_no, target, input_no, EdgeMode::Exact)
+      })
    }
 
    pub fn remove_edge(&mut self, flow_id: FlowId, edge_ix: EdgeIx) -> bool {
-      self.processors.processor_mut(flow_id).remove_edge(edge_ix);
-      self.flows[flow_id].remove_edge(edge_ix)
+      if self.flows.get(flow_id).and_then(|flow| flow.graph().edge_weight(edge_ix)).is_none() { return false }
+
+      let inverse = self.apply(Edit::RemoveEdge{flow_id, edge_ix});
+      self.history.record(inverse);
+      true
    }
 
+   pub fn add_feedback_edge(&mut self, flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo)
+      -> Result<(FeedbackIx, Type), EdgeError>
+   {
+      self.flows.get(flow_id).ok_or(EdgeError::UnknownFlow(flow_id))?;
+      let (feedback_ix, ty) = self.flows.alter(flow_id, |flow_store, flow| flow.add_feedback_edge(source, output_no, target, input_no, flow_store))?;
+      self.compile(flow_id);
+      self.history.record(Edit::RemoveFeedbackEdge{flow_id, feedback_ix});
+      Ok((feedback_ix, ty))
+   }
+
+   pub fn remove_feedback_edge(&mut self, flow_id: FlowId, feedback_ix: FeedbackIx) -> bool {
+      if !self.flows.get(flow_id).is_some_and(|flow| flow.feedback_edges().any(|(ix, ..)| ix == feedback_ix)) { return false }
+
+      let inverse = self.apply(Edit::RemoveFeedbackEdge{flow_id, feedback_ix});
+      self.history.record(inverse);
+      true
+   }
+
+   pub(super) fn set_converter(&mut self, flow_id: FlowId, node_ix: NodeIx, converter: bool) {
+      let inverse = self.apply(Edit::SetConverter{flow_id, node_ix, converter});
+      self.history.record(inverse);
+   }
+
+   // Sets the value fed into `input_no` of `node_ix` while it is unconnected, or with `None`
+   // falls back to the port's own default. Returns the value set before.
+   pub fn set_default(&mut self, flow_id: FlowId, node_ix: NodeIx, input_no: InputNo, value: Option<Value>)
+      -> Result<Option<Value>, DefaultError>
+   {
+      let inverse = self.change_default(flow_id, node_ix, input_no, value)?;
+      let old = match inverse { Edit::SetDefault{value, ..} => value, _ => unreachable!() };
+      self.history.record(inverse);
+      Ok(old)
+   }
+
+   // Like `set_default`, but leaves no undo step, for values played live, which would
+   // otherwise pile up in the history one key press at a time.
+   pub fn set_live_default(&mut self, flow_id: FlowId, node_ix: NodeIx, input_no: InputNo, value: Option<Value>)
+      -> Result<Option<Value>, DefaultError>
+   {
+      let inverse = self.change_default(flow_id, node_ix, input_no, value)?;
+      Ok(match inverse { Edit::SetDefault{value, ..} => value, _ => unreachable!() })
+   }
+
+   fn change_default(&mut self, flow_id: FlowId, node_ix: NodeIx, input_no: InputNo, value: Option<Value>) -> Result<Edit, DefaultError> {
+      let flow = self.flows.get(flow_id).ok_or(DefaultError::UnknownFlow(flow_id))?;
+      flow.check_default(node_ix, input_no, value, &self.flows)?;
+      Ok(self.apply(Edit::SetDefault{flow_id, node_ix, input_no, value}))
+   }
+
+   pub(super) fn snapshot(&self) -> Store {
+      Self{flows: self.flows.clone(), processors: ProcessorStore::build(&self.flows), history: History::new()}
+   }
+
+   pub(super) fn adopt_state(&mut self, old: &mut Store) {
+      self.processors.adopt_state(&mut old.processors)
+   }
+
+   pub(super) fn insert_flow(&mut self, flow_id: FlowId) {
+      self.flows.insert(flow_id, flow::Flow::new());
+      self.processors.insert(flow_id, Processor::new(flow_id));
+   }
+
+   pub(super) fn insert_nodes<I: IntoIterator<Item = (NodeIx, flow::Node)>>(&mut self, flow_id: FlowId, nodes: I) {
+      let nodes: Vec<_> = nodes.into_iter().collect();
+      self.flows.add_nodes_at(flow_id, nodes.iter().cloned());
+
+      let flows = &self.flows;
+      self.processors.instances_mut(flow_id, flows).into_iter().for_each(|(processor, paths)| {
+         paths.iter().for_each(|path| nodes.iter().for_each(|(node_ix, node)| processor.add_node(path, *node_ix, node, flows)));
+         processor.compile(flows);
+      });
+   }
+
+   pub fn begin_transaction(&mut self) { self.history.begin() }
+
+   pub fn commit_transaction(&mut self) { self.history.commit() }
+
+   pub fn transaction<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+      self.begin_transaction();
+      let result = f(self);
+      self.commit_transaction();
+      result
+   }
+
+   // Like `transaction`, but takes back the edits made so far if `f` fails.
+   pub fn try_transaction<T, E, F: FnOnce(&mut Self) -> Result<T, E>>(&mut self, f: F) -> Result<T, E> {
+      self.begin_transaction();
+      let mark = self.history.mark();
+      let result = f(self);
+      if result.is_err() {
+         let edits = self.history.rollback(mark);
+         self.apply_group(edits);
+      }
+      self.commit_transaction();
+      result
+   }
+
+   pub fn can_undo(&self) -> bool { self.history.can_undo() }
+   pub fn can_redo(&self) -> bool { self.history.can_redo() }
+
+   pub fn undo(&mut self) -> bool {
+      assert!(!self.history.in_transaction());
+      self.history.pop_undo().map(|group| {
+         let inverse = self.apply_group(group);
+         self.history.push_redo(inverse);
+      }).is_some()
+   }
+
+   pub fn redo(&mut self) -> bool {
+      assert!(!self.history.in_transaction());
+      self.history.pop_redo().map(|group| {
+         let inverse = self.apply_group(group);
+         self.history.push_undo(inverse);
+      }).is_some()
+   }
+
+   pub fn clear_history(&mut self) { self.history.clear() }
+
    pub fn compute_outplace<BufferRefMut>(
       &self, flow_id: FlowId, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRefMut>, buffer_sz: usize,
    ) where BufferRefMut: DerefMut<Target=Buffer>
    {
-      self.processors.compute_outplace(flow_id, output, input, buffer_sz, &self.flows)
+      self.processors.compute_outplace(flow_id, output, input, buffer_sz, &self.flows, None)
    }
-}
\ No newline at end of file
+
+   // Like `compute_outplace`, running the independent branches of the flow on `workers`. The
+   // output is the same.
+   pub fn compute_outplace_parallel<BufferRefMut>(
+      &self, workers: &rayon::ThreadPool, flow_id: FlowId, output: &mut LinearMap<OutputNo, BufferRefMut>, input: &LinearMap<InputNo, BufferRefMut>,
+      buffer_sz: usize,
+   ) where BufferRefMut: DerefMut<Target=Buffer>
+   {
+      self.processors.compute_outplace(flow_id, output, input, buffer_sz, &self.flows, Some(workers))
+   }
+
+   fn apply_group(&mut self, group: Vec<Edit>) -> Vec<Edit> {
+      group.into_iter().rev().map(|edit| self.apply(edit)).collect()
+   }
+
+   fn apply(&mut self, edit: Edit) -> Edit {
+      match edit {
+         Edit::AddFlow(None) => {
+            let flow_id = self.flows.add();
+            self.processors.add(flow_id);
+            Edit::RemoveFlow(flow_id)
+         }
+         Edit::AddFlow(Some(removed)) => {
+            let (flow_id, flow, processor) = *removed;
+            self.flows.insert(flow_id, flow);
+            self.processors.insert(flow_id, processor);
+            Edit::RemoveFlow(flow_id)
+         }
+         Edit::RemoveFlow(flow_id) =>
+            Edit::AddFlow(Some(Box::new((flow_id, self.flows.remove(flow_id), self.processors.remove(flow_id))))),
+         Edit::AddNode{flow_id, node, node_processors} => {
+            let node_ix = self.flows.add_node(flow_id, node.clone());
+            let flows = &self.flows;
+            let mut node_processors = node_processors.map(Vec::into_iter);
+            self.processors.instances_mut(flow_id, flows).into_iter().for_each(|(processor, paths)| {
+               paths.iter().for_each(|path| match node_processors.as_mut().and_then(Iterator::next) {
+                  Some(node_processor) => processor.insert_node_processor(path, node_processor),
+                  None => processor.add_node(path, node_ix, &node, flows),
+               });
+               processor.compile(flows);
+            });
+            Edit::RemoveNode{flow_id, node_ix}
+         }
+         Edit::RemoveNode{flow_id, node_ix} => {
+            let node = self.flows.remove_node(flow_id, node_ix).unwrap();
+            let flows = &self.flows;
+            let node_processors = self.processors.instances_mut(flow_id, flows).into_iter().flat_map(|(processor, paths)| {
+               let node_processors: Vec<_> = paths.iter().map(|path| processor.remove_node(path, node_ix)).collect();
+               processor.compile(flows);
+               node_processors
+            }).collect();
+            Edit::AddNode{flow_id, node, node_processors: Some(node_processors)}
+         }
+         Edit::AddEdge{flow_id, source, output_no, target, input_no} => {
+            let (edge_ix, _) = self.insert_edge(flow_id, source, output_no, target, input_no).unwrap();
+            Edit::RemoveEdge{flow_id, edge_ix}
+         }
+         Edit::RemoveEdge{flow_id, edge_ix} => {
+            let (source, output_no, target, input_no) = self.flows[flow_id].remove_edge(edge_ix).unwrap();
+            self.compile(flow_id);
+            Edit::AddEdge{flow_id, source, output_no, target, input_no}
+         }
+         Edit::AddFeedbackEdge{flow_id, feedback_ix, source, output_no, target, input_no} => {
+            self.flows.alter(flow_id, |flow_store, flow| flow.insert_feedback_edge(feedback_ix, source, output_no, target, input_no, flow_store)).unwrap();
+            self.compile(flow_id);
+            Edit::RemoveFeedbackEdge{flow_id, feedback_ix}
+         }
+         Edit::RemoveFeedbackEdge{flow_id, feedback_ix} => {
+            let (source, output_no, target, input_no) = self.flows[flow_id].remove_feedback_edge(feedback_ix).unwrap();
+            self.compile(flow_id);
+            Edit::AddFeedbackEdge{flow_id, feedback_ix, source, output_no, target, input_no}
+         }
+         Edit::SetConverter{flow_id, node_ix, converter} => {
+            let was_converter = self.flows[flow_id].set_converter(node_ix, converter);
+            Edit::SetConverter{flow_id, node_ix, converter: was_converter}
+         }
+         Edit::SetElement{flow_id, node_ix, element} => {
+            let old = self.flows[flow_id].set_element(node_ix, element);
+            let node = self.flows[flow_id].node(node_ix).clone();
+            let flows = &self.flows;
+            self.processors.instances_mut(flow_id, flows).into_iter().for_each(|(processor, paths)| {
+               paths.iter().for_each(|path| {
+                  processor.remove_node(path, node_ix);
+                  processor.add_node(path, node_ix, &node, flows);
+               });
+               processor.compile(flows);
+            });
+            Edit::SetElement{flow_id, node_ix, element: old}
+         }
+         Edit::SetParams{flow_id, params} => {
+            let old = self.flows[flow_id].set_params(params);
+            Edit::SetParams{flow_id, params: old}
+         }
+         Edit::SetDefault{flow_id, node_ix, input_no, value} => {
+            let old = self.flows[flow_id].set_default(node_ix, input_no, value);
+            self.compile(flow_id);
+            Edit::SetDefault{flow_id, node_ix, input_no, value: old}
+         }
+      }
+   }
+
+   fn insert_edge(&mut self, flow_id: FlowId, source: NodeIx, output_no: OutputNo, target: NodeIx, input_no: InputNo)
+      -> Result<(EdgeIx, Type), EdgeError>
+   {
+      let (edge_ix, ty) = self.flows.alter(flow_id, |flow_store, flow| flow.add_edge(source, output_no, target, input_no, flow_store))?;
+      self.compile(flow_id);
+      Ok((edge_ix, ty))
+   }
+
+   fn compile(&mut self, flow_id: FlowId) {
+      let flows = &self.flows;
+      self.processors.instances_mut(flow_id, flows).into_iter().for_each(|(processor, _)| processor.compile(flows))
+   }
+}
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use super::super::{PrimType, patch, prim_element::{PrimElement, BinaryOp}, processor::GenericSampledBuffer};
+
+   // The whole store as text, which also pins down the node and edge indices.
+   fn text(store: &Store) -> String {
+      let mut text = Vec::new();
+      patch::write(store, &mut text).unwrap();
+      String::from_utf8(text).unwrap()
+   }
+
+   fn sine(store: &mut Store) -> (FlowId, NodeIx) {
+      let flow_id = store.add_flow();
+      let osc = store.add_element(flow_id, element::Element::Prim(PrimElement::SineOscF32{f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(flow_id, "", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      store.add_edge(flow_id, osc, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      (flow_id, osc)
+   }
+
+   fn render(store: &Store, flow_id: FlowId) -> Vec<f32> {
+      let mut y = Buffer::new(Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+      let mut output: LinearMap<_, &mut Buffer> = vec![(OutputNo(0), &mut y)].into_iter().collect();
+      store.compute_outplace(flow_id, &mut output, &LinearMap::new(), 16);
+      drop(output);
+      unwrap_match!(y, Buffer::Sampled(GenericSampledBuffer::F32(y)) => y.samples)
+   }
+
+   #[test]
+   fn undo_and_redo_step_through_every_edit() {
+      let mut store = Store::new();
+      let mut states = vec![text(&store)];
+      let (flow_id, osc) = sine(&mut store);
+      states.push(text(&store));
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let gain = store.add_element(flow_id, element::Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      states.push(text(&store));
+      store.add_edge(flow_id, osc, OutputNo(0), gain, InputNo(0), EdgeMode::Exact).unwrap();
+      states.push(text(&store));
+      store.add_feedback_edge(flow_id, gain, OutputNo(0), gain, InputNo(1)).unwrap();
+      states.push(text(&store));
+      store.set_default(flow_id, gain, InputNo(1), Some(Value::F32(0.5.into()))).unwrap();
+      states.push(text(&store));
+      let (_, input) = store.add_input(flow_id, "freq", ty).unwrap();
+      states.push(text(&store));
+      store.add_edge(flow_id, input, OutputNo(0), osc, InputNo(0), EdgeMode::Exact).unwrap();
+      states.push(text(&store));
+      store.remove_node(flow_id, gain).unwrap();
+      states.push(text(&store));
+      store.remove_node(flow_id, osc).unwrap();
+      states.push(text(&store));
+      store.remove_flow_cascade(flow_id).unwrap();
+      states.push(text(&store));
+
+      // `sine` made four edits.
+      for state in states[1..].iter().rev().skip(1) {
+         assert!(store.undo());
+         assert_eq!(text(&store), *state);
+      }
+      (0..4).for_each(|_| assert!(store.undo()));
+      assert!(!store.undo());
+      assert_eq!(text(&store), states[0]);
+      (0..4).for_each(|_| assert!(store.redo()));
+      for state in &states[1..] {
+         assert_eq!(text(&store), *state);
+         store.redo();
+      }
+      assert!(!store.redo());
+   }
+
+   #[test]
+   fn transactions_are_undone_at_once() {
+      let mut store = Store::new();
+      let (flow_id, osc) = sine(&mut store);
+      let before = text(&store);
+      store.transaction(|store| {
+         store.add_input(flow_id, "freq", Type::Event(PrimType::F32)).unwrap();
+         store.remove_node(flow_id, osc).unwrap();
+      });
+      assert!(store.undo());
+      assert_eq!(text(&store), before);
+   }
+
+   // The oscillator carries on where it was, as if it had never been removed.
+   #[test]
+   fn undoing_a_removal_restores_the_state_of_the_node() {
+      let (mut store, mut reference) = (Store::new(), Store::new());
+      let ((flow_id, osc), _) = (sine(&mut store), sine(&mut reference));
+      render(&reference, flow_id);
+      render(&store, flow_id);
+      store.remove_node(flow_id, osc).unwrap();
+      store.undo();
+      assert_eq!(render(&store, flow_id), render(&reference, flow_id));
+   }
+
+   #[test]
+   fn converting_edges_insert_marked_converters() {
+      let mut store = Store::new();
+      let flow_id = store.add_flow();
+      let (actual, expected) = (Type::Sampled{ty: PrimType::I32, f_nyq: 22050}, Type::Sampled{ty: PrimType::F32, f_nyq: 1000});
+      let (_, input) = store.add_input(flow_id, "", actual).unwrap();
+      let (_, events) = store.add_input(flow_id, "", Type::Event(PrimType::F32)).unwrap();
+      let (_, output) = store.add_output(flow_id, "", expected).unwrap();
+      let (_, held) = store.add_output(flow_id, "", expected).unwrap();
+      let converters = |store: &Store| -> Vec<_> {
+         let flow = store.flow(flow_id).unwrap();
+         flow.converters().map(|node_ix| option_match!(flow.node(node_ix), flow::Node::Element(element) => element.clone())).collect()
+      };
+
+      assert_eq!(store.add_edge(flow_id, input, OutputNo(0), output, InputNo(0), EdgeMode::Exact), Err(EdgeError::TypeMismatch{expected, actual}));
+      let before = text(&store);
+      let (edge_ix, ty) = store.add_edge(flow_id, input, OutputNo(0), output, InputNo(0), EdgeMode::Convert).unwrap();
+      assert_eq!(ty, expected);
+      assert_eq!(store.flow(flow_id).unwrap().graph().edge_endpoints(edge_ix).map(|(_, target)| target), Some(output));
+      assert_eq!(converters(&store), vec![
+         Some(element::Element::Prim(PrimElement::Cast{from: PrimType::I32, to: PrimType::F32, f_nyq: 22050})),
+         Some(element::Element::Prim(PrimElement::Resample{ty: PrimType::F32, from_f_nyq: 22050, to_f_nyq: 1000})),
+      ]);
+      assert!(store.undo());
+      assert_eq!(text(&store), before);
+
+      store.add_edge(flow_id, events, OutputNo(0), held, InputNo(0), EdgeMode::Convert).unwrap();
+      assert_eq!(converters(&store), vec![Some(element::Element::Prim(PrimElement::Hold{ty: PrimType::F32, f_nyq: 1000}))]);
+   }
+
+   // Connecting the oscillator types the adder as its output, which the count no longer fits.
+   #[test]
+   fn failed_transactions_take_back_their_edits() {
+      let mut store = Store::new();
+      let (flow_id, osc) = sine(&mut store);
+      let before = text(&store);
+      let count = Type::Sampled{ty: PrimType::I32, f_nyq: 1000};
+      let result = store.try_transaction(|store| {
+         let (_, input) = store.add_input(flow_id, "count", count).unwrap();
+         let add = store.add_element(flow_id, element::Element::Prim(PrimElement::Binary{op: BinaryOp::Add, ty: PrimType::I32, f_nyq: 1000})).unwrap();
+         store.add_edge(flow_id, osc, OutputNo(0), add, InputNo(0), EdgeMode::Exact)?;
+         store.add_edge(flow_id, input, OutputNo(0), add, InputNo(1), EdgeMode::Exact)
+      });
+      assert_eq!(result, Err(EdgeError::TypeMismatch{expected: Type::Sampled{ty: PrimType::F32, f_nyq: 1000}, actual: count}));
+      assert_eq!(text(&store), before);
+      assert!(store.undo());
+      assert_ne!(text(&store), before);
+   }
+
+   #[test]
+   fn live_defaults_leave_no_undo_step() {
+      let mut store = Store::new();
+      let (flow_id, osc) = sine(&mut store);
+      store.set_default(flow_id, osc, InputNo(0), Some(Value::F32(100.0.into()))).unwrap();
+      (0..100).for_each(|n| { store.set_live_default(flow_id, osc, InputNo(0), Some(Value::F32((n as f32).into()))).unwrap(); });
+      assert_eq!(store.flow(flow_id).unwrap().default(osc, InputNo(0)), Some(Value::F32(99.0.into())));
+      assert!(store.undo());
+      assert_eq!(store.flow(flow_id).unwrap().default(osc, InputNo(0)), None);
+   }
+
+   #[test]
+   fn clearing_defaults_of_unknown_inputs_is_refused() {
+      let mut store = Store::new();
+      let (flow_id, osc) = sine(&mut store);
+      let (node_ix, input_no) = (NodeIx::new(99), InputNo(7));
+      assert_eq!(store.set_default(flow_id, node_ix, InputNo(0), None), Err(DefaultError::UnknownNode(node_ix)));
+      assert_eq!(store.set_default(flow_id, osc, input_no, None), Err(DefaultError::UnknownInput{node_ix: osc, input_no}));
+      assert_eq!(store.set_live_default(flow_id, osc, input_no, None), Err(DefaultError::UnknownInput{node_ix: osc, input_no}));
+      assert_eq!(store.set_default(flow_id, osc, InputNo(0), None), Ok(None));
+   }
+
+   #[test]
+   fn flows_cannot_contain_themselves() {
+      let mut store = Store::new();
+      let (inner, _) = sine(&mut store);
+      let outer = store.add_flow();
+      assert_eq!(store.add_element(inner, element::Element::Flow(inner)), Err(FlowError::Recursive{flow_id: inner, sub_flow_id: inner}));
+      store.add_element(outer, element::Element::Flow(inner)).unwrap();
+      assert_eq!(store.add_element(inner, element::Element::Flow(outer)), Err(FlowError::Recursive{flow_id: inner, sub_flow_id: outer}));
+      assert_eq!(store.add_element(inner, element::Element::Template(outer, vec![])), Err(FlowError::Recursive{flow_id: inner, sub_flow_id: outer}));
+   }
+
+   #[test]
+   fn flows_in_use_are_only_removed_with_their_users() {
+      let mut store = Store::new();
+      let (inner, _) = sine(&mut store);
+      let outer = store.add_flow();
+      store.add_element(outer, element::Element::Flow(inner)).unwrap();
+      assert_eq!(store.remove_flow(inner), Err(FlowError::InUse{flow_id: inner, users: vec![outer]}));
+      assert!(store.flow(inner).is_some());
+      store.remove_flow_cascade(inner).unwrap();
+      assert!(store.flow(inner).is_none());
+      assert_eq!(store.flow(outer).unwrap().nodes().count(), 0);
+      assert!(store.undo());
+      assert_eq!(store.flow(outer).unwrap().nodes().count(), 1);
+   }
+
+   #[test]
+   fn flows_can_be_walked_without_editing_them() {
+      let mut store = Store::new();
+      let (inner, _) = sine(&mut store);
+      let outer = store.add_flow();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let node = store.add_element(outer, element::Element::Flow(inner)).unwrap();
+      let gain = store.add_element(outer, element::Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      let (_, output) = store.add_output(outer, "out", ty).unwrap();
+      let (edge, _) = store.add_edge(outer, node, OutputNo(0), gain, InputNo(0), EdgeMode::Exact).unwrap();
+      store.add_edge(outer, gain, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      let (feedback, _) = store.add_feedback_edge(outer, gain, OutputNo(0), gain, InputNo(1)).unwrap();
+
+      let mut flows: Vec<_> = store.flows().map(|(flow_id, _)| flow_id).collect();
+      flows.sort_by_key(|flow_id| flow_id.0);
+      assert_eq!(flows, vec![inner, outer]);
+      assert_eq!(store.flows_referencing(inner).collect::<Vec<_>>(), vec![outer]);
+      assert_eq!(store.flows_referencing(outer).count(), 0);
+
+      let flow = store.flow(outer).unwrap();
+      assert_eq!(flow.references().collect::<Vec<_>>(), vec![inner]);
+      assert_eq!(flow.nodes().count(), 3);
+      assert!(matches!(flow.get_node(gain), Some(flow::Node::Element(element::Element::Prim(PrimElement::Binary{..})))));
+      assert!(matches!(flow.get_node(output), Some(flow::Node::Output{name, ..}) if name == "out"));
+      let mut edges: Vec<_> = flow.edges().map(|(_, source, target, edge)| (source, edge.output_no(), target, edge.input_no(), edge.ty())).collect();
+      edges.sort_by_key(|&(source, ..)| source);
+      assert_eq!(edges, vec![(node, OutputNo(0), gain, InputNo(0), ty), (gain, OutputNo(0), output, InputNo(0), ty)]);
+      let (source, target, weight) = flow.edge(edge).unwrap();
+      assert_eq!((source, target, weight.input_no()), (node, gain, InputNo(0)));
+      let feedback_edges: Vec<_> = flow.feedback_edges().map(|(ix, source, target, edge)| (ix, source, target, edge.input_no())).collect();
+      assert_eq!(feedback_edges, vec![(feedback, gain, gain, InputNo(1))]);
+      let position = |node_ix| flow.visit_order().position(|&ix| ix == node_ix).unwrap();
+      assert!(position(node) < position(gain) && position(gain) < position(output));
+
+      store.remove_node(outer, gain).unwrap();
+      let flow = store.flow(outer).unwrap();
+      assert!(flow.get_node(gain).is_none() && flow.edge(edge).is_none());
+      assert_eq!(flow.edges().count() + flow.feedback_edges().count(), 0);
+   }
+
+   #[test]
+   fn edits_of_unknown_flows_and_nodes_are_refused() {
+      let mut store = Store::new();
+      let flow_id = store.add_flow();
+      let (unknown, ty) = (FlowId(flow_id.0 + 1), Type::Event(PrimType::F32));
+      assert_eq!(store.add_input(unknown, "", ty), Err(FlowError::UnknownFlow(unknown)));
+      assert_eq!(store.add_output(unknown, "", ty), Err(FlowError::UnknownFlow(unknown)));
+      assert_eq!(store.remove_node(unknown, NodeIx::new(0)).err(), Some(FlowError::UnknownFlow(unknown)));
+      assert_eq!(store.remove_node(flow_id, NodeIx::new(0)).err(), Some(FlowError::UnknownNode{flow_id, node_ix: NodeIx::new(0)}));
+   }
+}
@@ -0,0 +1,292 @@
+use super::{
+   Type, PrimType, Value, InputNo, OutputNo,
+   flow::{Flow, Node, NodeIx},
+   flow_store::FlowStore,
+   element::Element,
+   prim_element::{PrimElement, cast_value},
+};
+
+use std::collections::BTreeMap;
+use linear_map::LinearMap;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Arg {
+   Rate(u64),
+   Type(PrimType),
+   Value(Value),
+}
+impl Arg {
+   pub(super) fn kind(self) -> usize {
+      match self {
+         Arg::Rate(_) => 0,
+         Arg::Type(_) => 1,
+         Arg::Value(_) => 2,
+      }
+   }
+}
+
+// A template parameter stands for the places of the flow it is bound to, which
+// `Element::Template` replaces with its arguments. The flow itself keeps what is written in it,
+// and the default is the argument it is meant to be used with.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Param {
+   pub name: String,
+   pub default: Arg,
+   pub bindings: Vec<Binding>,
+}
+
+// A place a parameter is bound to. `Field` counts the rates, types or values of a node,
+// whichever the parameter has, in the order they are written: the fields of a primitive
+// element, the rate and type of an input or output, or the arguments of a template. `Default`
+// is the value fed into an unconnected input, which only value parameters are bound to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Binding {
+   Field(NodeIx, usize),
+   Default(NodeIx, InputNo),
+}
+impl Binding {
+   pub fn node_ix(self) -> NodeIx {
+      match self {
+         Binding::Field(node_ix, _) | Binding::Default(node_ix, _) => node_ix,
+      }
+   }
+
+   // Whether the place exists in `flow` and can hold `arg`.
+   pub(super) fn check(self, flow: &Flow, arg: Arg, store: &FlowStore) -> bool {
+      let node = match flow.graph().node_weight(self.node_ix()) {
+         Some(node) => node,
+         None => return false,
+      };
+      match self {
+         Binding::Field(_, n) => {
+            let mut count = 0;
+            map_fields(node, |field, field_arg| {
+               if field_arg.kind() == arg.kind() { count = count.max(field + 1) }
+               field_arg
+            });
+            n < count
+         }
+         Binding::Default(_, input_no) =>
+            matches!((arg, node.input_types(store).get(&input_no)), (Arg::Value(_), Some(Type::Sampled{..} | Type::Bus{..}))),
+      }
+   }
+}
+
+// Rebuilds `node` with each of its rates, types and values passed through `f`, along with its
+// position among the fields of its kind. Constants take the type of their type field.
+fn map_fields<F: FnMut(usize, Arg) -> Arg>(node: &Node, f: F) -> Node {
+   let mut fields = Fields{counts: [0; 3], f};
+   match node {
+      Node::Element(Element::Prim(pe)) => Node::Element(Element::Prim(fields.prim(*pe))),
+      Node::Element(Element::PerChannel(pe, channels)) => Node::Element(Element::PerChannel(fields.prim(*pe), *channels)),
+      Node::Element(Element::Flow(flow_id)) => Node::Element(Element::Flow(*flow_id)),
+      Node::Element(Element::Template(flow_id, args)) =>
+         Node::Element(Element::Template(*flow_id, args.iter().map(|arg| fields.arg(*arg)).collect())),
+      Node::Input{no, ty, name} => Node::Input{no: *no, ty: fields.ty(*ty), name: name.clone()},
+      Node::Output{no, ty, name} => Node::Output{no: *no, ty: fields.ty(*ty), name: name.clone()},
+   }
+}
+
+struct Fields<F> {
+   counts: [usize; 3],
+   f: F,
+}
+impl<F: FnMut(usize, Arg) -> Arg> Fields<F> {
+   fn arg(&mut self, arg: Arg) -> Arg {
+      let n = self.counts[arg.kind()];
+      self.counts[arg.kind()] += 1;
+      (self.f)(n, arg)
+   }
+
+   fn rate(&mut self, f_nyq: u64) -> u64 { unwrap_match!(self.arg(Arg::Rate(f_nyq)), Arg::Rate(f_nyq) => f_nyq) }
+
+   fn prim_type(&mut self, ty: PrimType) -> PrimType { unwrap_match!(self.arg(Arg::Type(ty)), Arg::Type(ty) => ty) }
+
+   fn ty(&mut self, ty: Type) -> Type {
+      match ty {
+         Type::Sampled{ty, f_nyq} => Type::Sampled{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq)},
+         Type::Event(ty) => Type::Event(self.prim_type(ty)),
+         Type::Bus{ty, f_nyq, channels} => Type::Bus{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq), channels},
+      }
+   }
+
+   // Fields are visited in the order they are written in patches.
+   fn prim(&mut self, pe: PrimElement) -> PrimElement {
+      match pe {
+         PrimElement::SineOscF32{f_nyq} => PrimElement::SineOscF32{f_nyq: self.rate(f_nyq)},
+         PrimElement::Constant{value, f_nyq} => {
+            let (ty, value) = (value.Type(), unwrap_match!(self.arg(Arg::Value(value)), Arg::Value(value) => value));
+            let ty = self.prim_type(ty);
+            PrimElement::Constant{value: if ty == value.Type() { value } else { cast_value(value, ty) }, f_nyq: self.rate(f_nyq)}
+         }
+         PrimElement::Cast{from, to, f_nyq} => PrimElement::Cast{from: self.prim_type(from), to: self.prim_type(to), f_nyq: self.rate(f_nyq)},
+         PrimElement::Resample{ty, from_f_nyq, to_f_nyq} =>
+            PrimElement::Resample{ty: self.prim_type(ty), from_f_nyq: self.rate(from_f_nyq), to_f_nyq: self.rate(to_f_nyq)},
+         PrimElement::Hold{ty, f_nyq} => PrimElement::Hold{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq)},
+         PrimElement::Upmix{ty, f_nyq, channels} => PrimElement::Upmix{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq), channels},
+         PrimElement::Downmix{ty, f_nyq, channels} => PrimElement::Downmix{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq), channels},
+         PrimElement::Binary{op, ty, f_nyq} => PrimElement::Binary{op, ty: self.prim_type(ty), f_nyq: self.rate(f_nyq)},
+         PrimElement::Unary{op, ty, f_nyq} => PrimElement::Unary{op, ty: self.prim_type(ty), f_nyq: self.rate(f_nyq)},
+         PrimElement::Clamp{ty, f_nyq} => PrimElement::Clamp{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq)},
+         PrimElement::Mix{ty, f_nyq, inputs} => PrimElement::Mix{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq), inputs},
+         PrimElement::Select{ty, f_nyq, inputs} => PrimElement::Select{ty: self.prim_type(ty), f_nyq: self.rate(f_nyq), inputs},
+      }
+   }
+}
+
+// The arguments of an instance, by the places they are bound to: fields by node, kind and
+// position, and input defaults by node and input.
+pub(super) struct Substitution {
+   fields: LinearMap<(NodeIx, usize, usize), Arg>,
+   defaults: LinearMap<(NodeIx, InputNo), Value>,
+}
+impl Substitution {
+   pub(super) fn identity() -> Self {
+      Self{fields: LinearMap::new(), defaults: LinearMap::new()}
+   }
+
+   pub(super) fn new(flow: &Flow, args: &[Arg]) -> Self {
+      let mut substitution = Self::identity();
+      flow.params().iter().zip(args).filter(|(param, arg)| param.default.kind() == arg.kind()).for_each(|(param, &arg)| {
+         param.bindings.iter().for_each(|binding| match (*binding, arg) {
+            (Binding::Field(node_ix, n), arg) => { substitution.fields.insert((node_ix, arg.kind(), n), arg); }
+            (Binding::Default(node_ix, input_no), Arg::Value(value)) => { substitution.defaults.insert((node_ix, input_no), value); }
+            _ => {}
+         })
+      });
+      substitution
+   }
+
+   // Whether `args` fit the parameters of `flow`, each of the kind of its parameter.
+   pub(super) fn check(flow: &Flow, args: &[Arg]) -> bool {
+      flow.params().len() == args.len() && flow.params().iter().zip(args).all(|(param, arg)| param.default.kind() == arg.kind())
+   }
+
+   // Nested flows keep their own contents, only the arguments passed to templates change.
+   pub(super) fn node(&self, node_ix: NodeIx, node: &Node) -> Node {
+      if self.fields.is_empty() { return node.clone() }
+      map_fields(node, |n, arg| self.fields.get(&(node_ix, arg.kind(), n)).copied().unwrap_or(arg))
+   }
+
+   // The value bound to an input default, cast to the type of the input by the caller.
+   pub(super) fn default(&self, node_ix: NodeIx, input_no: InputNo) -> Option<Value> { self.defaults.get(&(node_ix, input_no)).copied() }
+
+   // The types of the inputs and outputs of the instance, in the order of their numbers.
+   pub(super) fn input_types(&self, flow: &Flow) -> LinearMap<InputNo, Type> {
+      let types: BTreeMap<_, _> = flow.nodes().filter(|(_, node)| matches!(node, Node::Input{..}))
+         .filter_map(|(node_ix, node)| option_match!(self.node(node_ix, node), Node::Input{no, ty, ..} => (no, ty)))
+         .collect();
+      types.into_iter().collect()
+   }
+
+   pub(super) fn output_types(&self, flow: &Flow) -> LinearMap<OutputNo, Type> {
+      let types: BTreeMap<_, _> = flow.nodes().filter(|(_, node)| matches!(node, Node::Output{..}))
+         .filter_map(|(node_ix, node)| option_match!(self.node(node_ix, node), Node::Output{no, ty, ..} => (no, ty)))
+         .collect();
+      types.into_iter().collect()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use super::super::{Store, EdgeMode, FlowId, engine, flow_store::FlowError, prim_element::BinaryOp, processor::{Buffer, GenericSampledBuffer}};
+
+   fn f32(x: f32) -> Value { Value::F32(x.into()) }
+
+   fn constant(store: &mut Store, flow: FlowId, value: Value) -> NodeIx {
+      store.add_element(flow, Element::Prim(PrimElement::Constant{value, f_nyq: 1000})).unwrap()
+   }
+
+   fn render(store: &Store, flow: FlowId) -> Vec<Buffer> {
+      engine::render_block_sizes(store, flow, 64, 64, &[64]).unwrap().into_iter().map(|(_, buffer)| buffer).collect()
+   }
+
+   fn samples(buffer: &Buffer) -> &[f32] {
+      unwrap_match!(buffer, Buffer::Sampled(GenericSampledBuffer::F32(buffer)) => &buffer.samples)
+   }
+
+   // A gain of 1 next to other ones: only the places bound to the parameter take the argument.
+   #[test]
+   fn arguments_replace_bound_places_only() {
+      let mut store = Store::new();
+      let template = store.add_flow();
+      let ty = Type::Sampled{ty: PrimType::F32, f_nyq: 1000};
+      let gain = constant(&mut store, template, f32(1.0));
+      let unity = constant(&mut store, template, f32(1.0));
+      let multiply = store.add_element(template, Element::Prim(PrimElement::Binary{op: BinaryOp::Multiply, ty: PrimType::F32, f_nyq: 1000})).unwrap();
+      store.set_default(template, multiply, InputNo(1), Some(f32(1.0))).unwrap();
+      for (no, source) in [gain, unity, multiply].iter().copied().enumerate() {
+         let (_, output) = store.add_output(template, "", ty).unwrap();
+         store.add_edge(template, source, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+         assert_eq!(store.flow(template).unwrap().output_types().nth(no).map(|(no, _)| no), Some(OutputNo(no as u32)));
+      }
+      store.add_edge(template, unity, OutputNo(0), multiply, InputNo(0), EdgeMode::Exact).unwrap();
+      store.set_params(template, vec![Param{
+         name: "gain".into(), default: Arg::Value(f32(1.0)), bindings: vec![Binding::Field(gain, 0), Binding::Default(multiply, InputNo(1))],
+      }]).unwrap();
+
+      let flow = store.add_flow();
+      let instance = store.add_element(flow, Element::Template(template, vec![Arg::Value(f32(0.25))])).unwrap();
+      for no in 0..3 {
+         let (_, output) = store.add_output(flow, "", ty).unwrap();
+         store.add_edge(flow, instance, OutputNo(no), output, InputNo(0), EdgeMode::Exact).unwrap();
+      }
+
+      let rendered = render(&store, flow);
+      assert!(samples(&rendered[0]).iter().all(|&x| x == 0.25));
+      assert!(samples(&rendered[1]).iter().all(|&x| x == 1.0));
+      assert!(samples(&rendered[2]).iter().all(|&x| x == 0.25));
+      let rendered = render(&store, template);
+      assert!(rendered.iter().all(|buffer| samples(buffer).iter().all(|&x| x == 1.0)));
+   }
+
+   #[test]
+   fn type_arguments_retype_bound_places() {
+      let mut store = Store::new();
+      let template = store.add_flow();
+      let node = constant(&mut store, template, f32(2.5));
+      let (_, output) = store.add_output(template, "", Type::Sampled{ty: PrimType::F32, f_nyq: 1000}).unwrap();
+      store.add_edge(template, node, OutputNo(0), output, InputNo(0), EdgeMode::Exact).unwrap();
+      store.set_params(template, vec![Param{
+         name: "type".into(), default: Arg::Type(PrimType::F32), bindings: vec![Binding::Field(node, 0), Binding::Field(output, 0)],
+      }]).unwrap();
+
+      let args = vec![Arg::Type(PrimType::I32)];
+      store.flow_store().check_instance(template, &args).unwrap();
+      let substitution = Substitution::new(store.flow(template).unwrap(), &args);
+      assert!(matches!(substitution.node(node, store.flow(template).unwrap().node(node)),
+         Node::Element(Element::Prim(PrimElement::Constant{value: Value::I32(3), f_nyq: 1000}))));
+      assert_eq!(Element::Template(template, args).output_types(store.flow_store()).get(&OutputNo(0)),
+         Some(&Type::Sampled{ty: PrimType::I32, f_nyq: 1000}));
+   }
+
+   #[test]
+   fn parameters_are_told_apart_by_name() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let (a, b) = (constant(&mut store, flow, f32(1.0)), constant(&mut store, flow, f32(1.0)));
+      let param = |name: &str, node_ix| Param{name: name.into(), default: Arg::Value(f32(1.0)), bindings: vec![Binding::Field(node_ix, 0)]};
+
+      assert!(store.set_params(flow, vec![param("a", a), param("b", b)]).is_ok());
+      assert_eq!(store.set_params(flow, vec![param("a", a), param("a", b)]), Err(FlowError::DuplicateParam{flow_id: flow, param: 1}));
+      assert_eq!(store.set_params(flow, vec![param("a", a), param("b", a)]),
+         Err(FlowError::Binding{flow_id: flow, param: 1, binding: Binding::Field(a, 0)}));
+      let rate = Param{name: "rate".into(), default: Arg::Rate(1000), bindings: vec![Binding::Field(a, 1)]};
+      assert_eq!(store.set_params(flow, vec![rate]), Err(FlowError::Binding{flow_id: flow, param: 0, binding: Binding::Field(a, 1)}));
+   }
+
+   #[test]
+   fn removing_a_node_unbinds_it_until_undone() {
+      let mut store = Store::new();
+      let flow = store.add_flow();
+      let (a, b) = (constant(&mut store, flow, f32(1.0)), constant(&mut store, flow, f32(1.0)));
+      let params = vec![Param{name: "x".into(), default: Arg::Value(f32(1.0)), bindings: vec![Binding::Field(a, 0), Binding::Field(b, 0)]}];
+      store.set_params(flow, params.clone()).unwrap();
+
+      store.remove_node(flow, a).unwrap();
+      assert_eq!(store.flow(flow).unwrap().params()[0].bindings, vec![Binding::Field(b, 0)]);
+      store.undo();
+      assert_eq!(store.flow(flow).unwrap().params(), &params[..]);
+   }
+}